@@ -0,0 +1,226 @@
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{DNSClass, LowerName, RData, RecordType},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::CacheConfig;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: LowerName,
+    pub query_type: RecordType,
+    pub query_class: DNSClass,
+}
+
+struct CacheEntry {
+    message: Message,
+    inserted: Instant,
+    ttl: Duration,
+    /// Provider the response came from
+    provider: String,
+}
+
+impl CacheEntry {
+    fn is_expired(&self) -> bool {
+        self.inserted.elapsed() >= self.ttl
+    }
+}
+
+/// Storage of upstream responses, looked up before a query is raced and filled
+/// with its answer. Implementing it on a shared store, e.g. Redis, lets several
+/// instances share one cache.
+#[async_trait::async_trait]
+pub trait DnsCache: Send + Sync {
+    /// Returns the cached message with record TTLs reduced by the time spent in
+    /// the cache, along with the time until it expires
+    async fn get(&self, key: &CacheKey) -> Option<(Message, Duration)>;
+
+    /// Caches a response from `provider` for `ttl`
+    async fn insert(&self, key: CacheKey, message: Message, ttl: Duration, provider: &str);
+
+    /// Drops the entries of providers for which `keep` returns false, called when a
+    /// reload changes providers, and returns the number of entries left
+    async fn retain_providers(&self, keep: &(dyn for<'p> Fn(&'p str) -> bool + Sync)) -> usize;
+}
+
+/// Creates the built-in in-memory cache
+pub fn create(config: &CacheConfig) -> Arc<dyn DnsCache> {
+    Arc::new(MemoryCache::new(
+        config.max_entries,
+        config.min_served_ttl,
+        config.max_served_ttl,
+    ))
+}
+
+/// In-memory cache of upstream responses, expired by TTL. Upstream queries carry
+/// no EDNS Client Subnet, so every client shares the same entries: an ECS scope
+/// in a response refers to the proxy's own address.
+pub struct MemoryCache {
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+    max_entries: usize,
+    /// Lower bound on the record TTLs reported in cached answers
+    min_served_ttl: u32,
+    /// Upper bound on the record TTLs reported in cached answers
+    max_served_ttl: u32,
+}
+
+impl MemoryCache {
+    pub fn new(
+        max_entries: usize,
+        min_served_ttl: Option<u32>,
+        max_served_ttl: Option<u32>,
+    ) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+            min_served_ttl: min_served_ttl.unwrap_or(0),
+            max_served_ttl: max_served_ttl.unwrap_or(u32::MAX),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsCache for MemoryCache {
+    /// Also keeps record TTLs between the minimum and maximum served TTL
+    async fn get(&self, key: &CacheKey) -> Option<(Message, Duration)> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        if entry.is_expired() {
+            entries.remove(key);
+            return None;
+        }
+
+        let mut message = entry.message.clone();
+        let elapsed = entry.inserted.elapsed().as_secs() as u32;
+        let remaining = |ttl: u32| {
+            ttl.saturating_sub(elapsed)
+                .clamp(self.min_served_ttl, self.max_served_ttl)
+        };
+        for record in message.answers_mut() {
+            record.set_ttl(remaining(record.ttl()));
+        }
+        for record in message.name_servers_mut() {
+            record.set_ttl(remaining(record.ttl()));
+        }
+        Some((message, entry.ttl.saturating_sub(entry.inserted.elapsed())))
+    }
+
+    async fn insert(&self, key: CacheKey, message: Message, ttl: Duration, provider: &str) {
+        if ttl.is_zero() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&key) && entries.len() >= self.max_entries {
+            entries.retain(|_, entry| !entry.is_expired());
+            if entries.len() >= self.max_entries {
+                tracing::debug!("Cache is full, not caching {}", key.name);
+                return;
+            }
+        }
+
+        entries.insert(
+            key,
+            CacheEntry {
+                message,
+                inserted: Instant::now(),
+                ttl,
+                provider: provider.to_string(),
+            },
+        );
+    }
+
+    /// Also drops expired entries
+    async fn retain_providers(&self, keep: &(dyn for<'p> Fn(&'p str) -> bool + Sync)) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| !entry.is_expired() && keep(&entry.provider));
+        entries.len()
+    }
+}
+
+/// Keys whose entries are being refreshed in the background
+#[derive(Default)]
+pub struct Refreshes {
+    keys: Mutex<HashSet<CacheKey>>,
+}
+
+/// Marks a key as being refreshed until dropped
+pub struct Refresh {
+    refreshes: Arc<Refreshes>,
+    key: CacheKey,
+}
+
+impl Refreshes {
+    /// Registers a background refresh of `key`, or returns `None` if one is already running
+    pub fn begin(self: &Arc<Self>, key: CacheKey) -> Option<Refresh> {
+        if !self.keys.lock().unwrap().insert(key.clone()) {
+            return None;
+        }
+        Some(Refresh {
+            refreshes: self.clone(),
+            key,
+        })
+    }
+}
+
+impl Drop for Refresh {
+    fn drop(&mut self) {
+        self.refreshes.keys.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Negative caching TTL per RFC 2308: the lesser of the SOA record's TTL and
+/// its MINIMUM field. Responses without an SOA must not be cached.
+pub fn negative_ttl(message: &Message) -> Option<Duration> {
+    if message.response_code() != ResponseCode::NXDomain {
+        return None;
+    }
+
+    message
+        .name_servers()
+        .iter()
+        .find_map(|record| match record.data() {
+            RData::SOA(soa) => Some(Duration::from_secs(u64::from(
+                record.ttl().min(soa.minimum()),
+            ))),
+            _ => None,
+        })
+}
+
+/// Caching TTL of a response with answers: the lowest TTL among its answer records
+pub fn positive_ttl(message: &Message) -> Option<Duration> {
+    if message.response_code() != ResponseCode::NoError {
+        return None;
+    }
+
+    message
+        .answers()
+        .iter()
+        .map(|record| record.ttl())
+        .min()
+        .map(|ttl| Duration::from_secs(u64::from(ttl)))
+}
+
+/// Keeps the leading `prefix` bits of an address
+pub fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix.min(32)))
+                .unwrap_or(0);
+            IpAddr::V4((u32::from(addr) & mask).into())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix.min(128)))
+                .unwrap_or(0);
+            IpAddr::V6((u128::from(addr) & mask).into())
+        }
+    }
+}
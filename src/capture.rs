@@ -0,0 +1,234 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        OnceLock,
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// pcap link type for raw IPv4/IPv6 packets without a link-layer header
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const IPPROTO_UDP: u8 = 17;
+/// Exchanges waiting for the writer thread; more are dropped rather than
+/// holding up the queries
+const QUEUE_CAPACITY: usize = 1024;
+/// Server port written for every exchange. Requests do not carry the port
+/// they arrived on, so the standard DNS port is used for capture tools to
+/// dissect the payload.
+const SERVER_PORT: u16 = 53;
+
+static CAPTURE: OnceLock<Capture> = OnceLock::new();
+
+/// Writes sampled queries and responses to a pcap file, wrapped in synthetic
+/// IP/UDP headers between the client and port 53. The file is
+/// written on a thread of its own, so recording never blocks a query.
+pub struct Capture {
+    exchanges: SyncSender<Exchange>,
+    sample_rate: f64,
+}
+
+/// A query and its response, as queued for the writer thread
+struct Exchange {
+    time: Duration,
+    client: SocketAddr,
+    server: SocketAddr,
+    query: Vec<u8>,
+    response: Vec<u8>,
+}
+
+/// Creates the capture file; must be called at most once, before serving queries.
+/// `sample_rate` must be between 0.0 and 1.0.
+pub fn init(path: &str, sample_rate: f64) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&0xa1b2_c3d4_u32.to_le_bytes())?;
+    writer.write_all(&2_u16.to_le_bytes())?;
+    writer.write_all(&4_u16.to_le_bytes())?;
+    writer.write_all(&0_i32.to_le_bytes())?;
+    writer.write_all(&0_u32.to_le_bytes())?;
+    writer.write_all(&SNAPLEN.to_le_bytes())?;
+    writer.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+    writer.flush()?;
+
+    let (exchanges, queue) = mpsc::sync_channel(QUEUE_CAPACITY);
+    thread::Builder::new()
+        .name("capture".to_string())
+        .spawn(move || write_exchanges(writer, queue))?;
+    let capture = Capture {
+        exchanges,
+        sample_rate,
+    };
+    CAPTURE
+        .set(capture)
+        .map_err(|_| io::Error::other("packet capture already initialized"))
+}
+
+/// Returns the capture if one is enabled and the current exchange is sampled
+pub fn sampled() -> Option<&'static Capture> {
+    CAPTURE
+        .get()
+        .filter(|capture| rand::random_bool(capture.sample_rate))
+}
+
+impl Capture {
+    /// Queues a query from `client` and the response sent back to it for writing
+    pub fn record(&self, client: SocketAddr, query: Vec<u8>, response: Vec<u8>) {
+        let server = SocketAddr::new(
+            match client.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            },
+            SERVER_PORT,
+        );
+        let exchange = Exchange {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            client,
+            server,
+            query,
+            response,
+        };
+        match self.exchanges.try_send(exchange) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("Packet capture is falling behind, dropping an exchange");
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                tracing::error!("Packet capture writer has stopped");
+            }
+        }
+    }
+}
+
+/// Writes queued exchanges until the capture is dropped, flushing whenever the
+/// queue runs empty
+fn write_exchanges(mut writer: BufWriter<File>, queue: Receiver<Exchange>) {
+    while let Ok(exchange) = queue.recv() {
+        let result = std::iter::once(exchange)
+            .chain(queue.try_iter())
+            .try_for_each(|exchange| {
+                write_packet(
+                    &mut writer,
+                    exchange.time,
+                    exchange.client,
+                    exchange.server,
+                    &exchange.query,
+                )?;
+                write_packet(
+                    &mut writer,
+                    exchange.time,
+                    exchange.server,
+                    exchange.client,
+                    &exchange.response,
+                )
+            })
+            .and_then(|()| writer.flush());
+        if let Err(e) = result {
+            tracing::error!("Failed to write packet capture: {}", e);
+        }
+    }
+}
+
+fn write_packet(
+    writer: &mut impl Write,
+    time: Duration,
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: &[u8],
+) -> io::Result<()> {
+    let packet = ip_udp_packet(src, dst, payload);
+    let orig_len = packet.len() as u32;
+    let incl_len = orig_len.min(SNAPLEN);
+
+    writer.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+    writer.write_all(&time.subsec_micros().to_le_bytes())?;
+    writer.write_all(&incl_len.to_le_bytes())?;
+    writer.write_all(&orig_len.to_le_bytes())?;
+    writer.write_all(&packet[..incl_len as usize])
+}
+
+/// Builds an IPv4 or IPv6 packet carrying `payload` in a UDP datagram
+fn ip_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    // Responses over TCP can exceed what fits in a single IP packet; truncate them
+    let payload = &payload[..payload.len().min(usize::from(u16::MAX) - 48)];
+    let udp_len = (8 + payload.len()) as u16;
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(40 + udp.len());
+    let mut pseudo_header = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut header = [0_u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&(20 + udp_len).to_be_bytes());
+            header[8] = 64;
+            header[9] = IPPROTO_UDP;
+            header[12..16].copy_from_slice(&src_ip.octets());
+            header[16..20].copy_from_slice(&dst_ip.octets());
+            let checksum = internet_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&header);
+
+            let mut pseudo = Vec::with_capacity(12);
+            pseudo.extend_from_slice(&src_ip.octets());
+            pseudo.extend_from_slice(&dst_ip.octets());
+            pseudo.extend_from_slice(&[0, IPPROTO_UDP]);
+            pseudo.extend_from_slice(&udp_len.to_be_bytes());
+            pseudo
+        }
+        (src_ip, dst_ip) => {
+            let src_ip = to_ipv6(src_ip);
+            let dst_ip = to_ipv6(dst_ip);
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.extend_from_slice(&[IPPROTO_UDP, 64]);
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&src_ip.octets());
+            pseudo.extend_from_slice(&dst_ip.octets());
+            pseudo.extend_from_slice(&u32::from(udp_len).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+            pseudo
+        }
+    };
+
+    pseudo_header.extend_from_slice(&udp);
+    // A computed checksum of zero is transmitted as all ones (RFC 768)
+    let checksum = match internet_checksum(&pseudo_header) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// One's complement sum of 16-bit words (RFC 1071)
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u64 = data
+        .chunks(2)
+        .map(|chunk| u64::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
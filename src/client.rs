@@ -2,37 +2,178 @@ use anyhow::Result;
 use hickory_client::{
     client::{Client, ClientHandle},
     proto::{
+        op::Query,
         rr::{DNSClass, Name, RecordType},
-        runtime::TokioRuntimeProvider,
+        runtime::{RuntimeProvider, TokioRuntimeProvider},
+        xfer::{DnsHandle, DnsRequestOptions, FirstAnswer},
     },
 };
-use hickory_proto::{h2::HttpsClientStreamBuilder, xfer::DnsResponse};
+use hickory_proto::{ProtoError, ProtoErrorKind, h2::HttpsClientStreamBuilder, xfer::DnsResponse};
 use rustls::ClientConfig;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 use tokio::sync::watch;
 
-use crate::config::DomainRules;
+use crate::{
+    config::DomainRules, json_doh::JsonDohClient, runtime::UpstreamRuntimeProvider,
+    stats::LatencyAverage, tls_info,
+};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const QUERY_INITIAL_RETRY_DELAY: u64 = 200;
+const QUERY_MAX_RETRY_DELAY: u64 = 600;
+/// Timeout of the probe query that selects one of several DoH paths
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Delay before retrying a connection rotation that failed; the old connection is kept meanwhile
+const ROTATION_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Errors returned by [`RetryableClient::query`]
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ClientError {
+    /// The upstream did not answer within the query timeout
+    #[error("query timed out after {0:?}")]
+    Timeout(Duration),
+    /// No connection to the upstream could be established
+    #[error("connection failed")]
+    Connect(#[source] ProtoError),
+    /// The upstream connection returned an error for the query
+    #[error("protocol error")]
+    Protocol(#[source] hickory_client::ClientError),
+    /// The upstream response exceeded the configured size limit
+    #[error("response of {size} bytes exceeds the {limit} byte limit")]
+    ResponseTooLarge { size: usize, limit: usize },
+    /// The JSON DoH API request failed or returned an unusable response
+    #[error("JSON API error: {0}")]
+    Json(String),
+    /// All retries were used up; `last` holds the error of the final attempt, if any
+    #[error("max retries exceeded after {retries} retries")]
+    MaxRetries {
+        retries: u32,
+        #[source]
+        last: Option<Box<ClientError>>,
+    },
+}
 
 #[derive(Clone)]
 pub struct RetryableClient {
     dns_name: String,
-    addr: SocketAddr,
     client: watch::Receiver<ClientHolder>,
+    connector: Arc<Connector>,
+    reconnect_tx: tokio::sync::mpsc::Sender<()>,
+    options: ClientOptions,
+    /// Set for providers queried through the JSON API instead of wire format
+    json: Option<Arc<JsonDohClient>>,
+}
+
+/// Establishes upstream connections; shared with the background reconnect task,
+/// which exits once every `RetryableClient` handle has been dropped
+struct Connector {
+    dns_name: String,
+    addr: SocketAddr,
     client_sender: watch::Sender<ClientHolder>,
     client_config: Arc<ClientConfig>,
-    reconnect_tx: tokio::sync::mpsc::Sender<()>,
+    jitter: f64,
+    /// Set when connections need a DSCP mark or a network namespace
+    runtime: Option<UpstreamRuntimeProvider>,
+    log_tls_details: bool,
+    paths: Vec<String>,
+    /// Index of the path that last answered the probe query
+    working_path: AtomicUsize,
+}
+
+/// Per-provider settings for [`RetryableClient`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientOptions {
+    /// Upstream responses larger than this many bytes are discarded
+    pub max_response_size: usize,
+    /// Fraction of each backoff delay that is randomized (0.0 = none, 1.0 = full jitter)
+    pub jitter: f64,
+    /// Retries of a failed query before giving up
+    pub max_retries: u32,
+    /// Timeout for each query attempt; defaults to [`QUERY_TIMEOUT`]
+    pub query_timeout: Option<Duration>,
+    /// URL path of the JSON DoH API; when set, queries use it instead of RFC 8484 wire format
+    pub json_path: Option<String>,
+    /// Keep one long-lived connection; when false, every query attempt opens a new one
+    pub reuse: bool,
+    /// DSCP value to mark upstream connections with
+    pub dscp: Option<u8>,
+    /// Network namespace to open upstream connections in (Linux)
+    pub netns: Option<String>,
+    /// Log the TLS details of every new connection at debug level
+    pub log_tls_details: bool,
+    /// Replace the persistent connection once it is this old, even if it still works
+    pub max_connection_lifetime: Option<Duration>,
+    /// URL paths of the wire format endpoint; with several, each new connection
+    /// probes them in order, starting from the one that worked last
+    pub paths: Vec<String>,
+    /// RD bit of upstream queries; cleared for upstreams that front authoritative servers
+    pub recursion_desired: bool,
+}
+
+/// A response with the timing of the attempt that produced it, so that
+/// warm-path and cold-path latency can be told apart
+pub struct QueryResponse {
+    pub response: DnsResponse,
+    /// Round trip of the successful attempt alone, without connection setup and earlier attempts
+    pub rtt: Duration,
+    /// Whether a connection had to be (re)established before the successful attempt
+    pub reconnected: bool,
 }
 
 pub struct DnsClientEntry {
     pub client: RetryableClient,
     pub name: String,
     pub domain_rules: DomainRules,
+    pub drop_types: Vec<RecordType>,
+    pub authoritative_negative: bool,
+    pub tier: u8,
+    /// Failover order within a tier, lower first
+    pub priority: u8,
+    /// Query classes this provider serves; empty means all classes
+    pub query_classes: Vec<DNSClass>,
+    pub transport: Transport,
+    /// Queried and logged, but its answers never win or serve as fallback
+    pub monitor: bool,
+    /// Whether its answers are logged per query
+    pub log: bool,
+    /// Average latency of its queries, kept across reloads
+    pub latency: Arc<LatencyAverage>,
+}
+
+/// Protocol used to reach a provider, shown in logs to compare providers by transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// DNS over HTTPS (RFC 8484)
+    Doh,
+    /// DNS over HTTPS using the JSON API
+    DohJson,
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Doh => f.write_str("doh"),
+            Self::DohJson => f.write_str("doh-json"),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct ClientHolder {
     client: Option<Client>,
     version: u64,
+    /// When the current connection was established
+    created: Option<Instant>,
+    connect_error: Option<ProtoError>,
+    /// Whether a connection was ever established
+    connected_once: bool,
 }
 
 impl RetryableClient {
@@ -40,70 +181,147 @@ impl RetryableClient {
         addr: SocketAddr,
         dns_name: &str,
         client_config: Arc<ClientConfig>,
+        options: ClientOptions,
     ) -> Result<Self> {
+        // JSON API providers use their own HTTP client and providers without connection
+        // reuse connect per query; neither needs a persistent connection, so they count
+        // as connected from the start
+        let json = match &options.json_path {
+            Some(path) => Some(Arc::new(JsonDohClient::new(
+                addr,
+                dns_name,
+                path,
+                &client_config,
+            )?)),
+            None => None,
+        };
+        let persistent = json.is_none() && options.reuse;
+
         let client_holder = ClientHolder {
             client: None,
             version: 0,
+            created: None,
+            connect_error: None,
+            connected_once: !persistent,
         };
         let (tx, rx) = watch::channel(client_holder);
         let (reconnect_tx, mut reconnect_rx) = tokio::sync::mpsc::channel(100);
 
-        let reconnect_client = Self {
+        let connector = Arc::new(Connector {
             dns_name: dns_name.to_string(),
             addr,
-            client: rx.clone(),
-            client_sender: tx.clone(),
-            client_config: client_config.clone(),
-            reconnect_tx: reconnect_tx.clone(),
-        };
-
-        tokio::spawn(async move {
-            // initialize the connection
-            reconnect_client.handle_reconnect().await;
-            // wait for the reconnection signal
-            while reconnect_rx.recv().await.is_some() {
-                reconnect_client.handle_reconnect().await;
-            }
+            client_sender: tx,
+            client_config,
+            jitter: options.jitter,
+            runtime: UpstreamRuntimeProvider::new(options.dscp, options.netns.as_deref()),
+            log_tls_details: options.log_tls_details,
+            paths: options.paths.clone(),
+            working_path: AtomicUsize::new(0),
         });
 
+        let reconnect_connector = connector.clone();
+        let max_lifetime = options.max_connection_lifetime;
+        if persistent {
+            tokio::spawn(async move {
+                // initialize the connection
+                reconnect_connector.handle_reconnect().await;
+                let mut retry_rotation_at = None;
+                loop {
+                    let rotate_at = max_lifetime
+                        .and_then(|lifetime| {
+                            reconnect_connector.connected_at().map(|at| at + lifetime)
+                        })
+                        .map(|at| retry_rotation_at.map_or(at, |retry: Instant| retry.max(at)));
+                    tokio::select! {
+                        // wait for the reconnection signal
+                        signal = reconnect_rx.recv() => {
+                            if signal.is_none() {
+                                break;
+                            }
+                            reconnect_connector.handle_reconnect().await;
+                        }
+                        _ = tokio::time::sleep_until(rotate_at.unwrap_or_else(Instant::now).into()),
+                            if rotate_at.is_some() =>
+                        {
+                            retry_rotation_at = (!reconnect_connector.rotate().await)
+                                .then(|| Instant::now() + ROTATION_RETRY_DELAY);
+                        }
+                    }
+                }
+            });
+        }
+
         Ok(Self {
             dns_name: dns_name.to_string(),
-            addr,
             client: rx,
-            client_sender: tx,
-            client_config,
+            connector,
             reconnect_tx,
+            options,
+            json,
         })
     }
 
-    async fn create_client(
+    /// Whether this client was created with the given upstream, TLS configuration
+    /// and options, so it can be kept across a configuration reload
+    pub fn matches(
+        &self,
         addr: SocketAddr,
         dns_name: &str,
-        client_config: Arc<ClientConfig>,
-    ) -> Result<Client> {
-        tracing::debug!(target: concat!(module_path!(), "::stdout"), "Creating HTTPS connection to {}", dns_name);
+        client_config: &Arc<ClientConfig>,
+        options: &ClientOptions,
+    ) -> bool {
+        self.connector.addr == addr
+            && self.dns_name == dns_name
+            && Arc::ptr_eq(&self.connector.client_config, client_config)
+            && self.options == *options
+    }
 
-        let provider = TokioRuntimeProvider::new();
-        let https_builder = HttpsClientStreamBuilder::with_client_config(client_config, provider);
-        let connect = https_builder.build(addr, dns_name.to_string(), "/dns-query".to_string());
-        tracing::debug!(target: concat!(module_path!(), "::stdout"), "Connecting AsyncClient: {}", dns_name);
-        let (client, bg) = Client::connect(connect).await?;
-        tokio::spawn(bg);
-        Ok(client)
+    /// Whether this client has established a connection at least once
+    pub fn has_connected(&self) -> bool {
+        self.client.borrow().connected_once
     }
 
+    /// Waits until a connection is available; providers without a persistent
+    /// connection are always available
+    pub async fn wait_connected(&self) {
+        let mut receiver = self.client.clone();
+        let _ = receiver
+            .wait_for(|holder| holder.client.is_some() || holder.connected_once)
+            .await;
+    }
+
+    /// The timeout of each query attempt: `timeout` if set, else the client's
+    /// configured query timeout
+    pub fn attempt_timeout(&self, timeout: Option<Duration>) -> Duration {
+        timeout
+            .or(self.options.query_timeout)
+            .unwrap_or(QUERY_TIMEOUT)
+    }
+
+    /// Sends a query, retrying across reconnects. `timeout` applies to each
+    /// attempt and takes precedence over the client's configured query timeout.
     pub async fn query(
         &self,
         name: Name,
         query_class: DNSClass,
         query_type: RecordType,
-    ) -> Result<DnsResponse> {
-        const MAX_RETRIES: u32 = 6;
-        const INITIAL_RETRY_DELAY: u64 = 200;
-        const MAX_RETRY_DELAY: u64 = 600;
+        timeout: Option<Duration>,
+    ) -> Result<QueryResponse, ClientError> {
         let mut retries = 0;
         let mut receiver = self.client.clone();
         let mut reconnect_sent = false;
+        let mut last_error = None;
+        let query_timeout = self.attempt_timeout(timeout);
+        if let Some(json) = &self.json {
+            return self
+                .query_json(json, name, query_class, query_type, query_timeout)
+                .await;
+        }
+        if !self.options.reuse {
+            return self
+                .query_fresh(name, query_class, query_type, query_timeout)
+                .await;
+        }
 
         loop {
             let client_holder = {
@@ -112,14 +330,16 @@ impl RetryableClient {
             };
 
             if let Some(mut client) = client_holder.client {
+                let start = Instant::now();
                 match tokio::time::timeout(
-                    Duration::from_secs(3),
-                    client.query(name.clone(), query_class, query_type),
+                    query_timeout,
+                    self.send_query(&mut client, name.clone(), query_class, query_type),
                 )
                 .await
                 {
                     Ok(result) => match result {
                         Ok(response) => {
+                            let rtt = start.elapsed();
                             if retries > 0 {
                                 tracing::debug!(
                                     "Query success after {} retries, <{}>",
@@ -127,7 +347,12 @@ impl RetryableClient {
                                     self.dns_name
                                 );
                             }
-                            return Ok(response);
+                            // Every failed attempt drops the connection, so a retry means a reconnect
+                            return Ok(QueryResponse {
+                                response: self.check_size(&name, response)?,
+                                rtt,
+                                reconnected: retries > 0,
+                            });
                         }
                         Err(e) => {
                             tracing::warn!(
@@ -136,13 +361,15 @@ impl RetryableClient {
                                 e,
                                 self.dns_name
                             );
+                            last_error = Some(ClientError::Protocol(e));
                         }
                     },
                     Err(_) => {
                         tracing::warn!("Query timeout for <{}>, <{}>", name, self.dns_name);
+                        last_error = Some(ClientError::Timeout(query_timeout));
                     }
                 }
-                self.client_sender.send_if_modified(|inner| {
+                self.connector.client_sender.send_if_modified(|inner| {
                     if inner.version == client_holder.version {
                         inner.client = None;
                         inner.version += 1;
@@ -151,10 +378,19 @@ impl RetryableClient {
                         false
                     }
                 });
+            } else if let Some(e) = client_holder.connect_error {
+                last_error = Some(ClientError::Connect(e));
             }
 
-            if retries >= MAX_RETRIES {
-                return Err(anyhow::anyhow!("Max retries exceeded"));
+            if retries >= self.options.max_retries {
+                if !reconnect_sent {
+                    // Fail without waiting, but have a connection ready for later queries
+                    let _ = self.reconnect_tx.try_send(());
+                }
+                return Err(ClientError::MaxRetries {
+                    retries,
+                    last: last_error.map(Box::new),
+                });
             }
 
             if !reconnect_sent {
@@ -172,21 +408,283 @@ impl RetryableClient {
                 }
             }
 
-            let delay = INITIAL_RETRY_DELAY
-                .saturating_mul(2_u64.saturating_pow(retries))
-                .min(MAX_RETRY_DELAY);
-            tokio::time::sleep(Duration::from_millis(delay)).await;
+            tokio::time::sleep(self.retry_delay(retries)).await;
+            retries += 1;
+        }
+    }
+
+    async fn query_json(
+        &self,
+        json: &JsonDohClient,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        query_timeout: Duration,
+    ) -> Result<QueryResponse, ClientError> {
+        let name = &name;
+        self.retry(name, query_timeout, || async move {
+            let start = Instant::now();
+            let message = json
+                .query(
+                    name,
+                    query_class,
+                    query_type,
+                    self.options.max_response_size,
+                )
+                .await
+                .map_err(|e| {
+                    tracing::warn!(
+                        "JSON query failed for <{}>: {:#}, <{}>",
+                        name,
+                        e,
+                        self.dns_name
+                    );
+                    ClientError::Json(format!("{e:#}"))
+                })?;
+            Ok(QueryResponse {
+                response: DnsResponse::from_message(message)
+                    .map_err(|e| ClientError::Json(e.to_string()))?,
+                rtt: start.elapsed(),
+                reconnected: false,
+            })
+        })
+        .await
+    }
+
+    /// Queries over a new connection per attempt, for providers with `reuse = false`
+    async fn query_fresh(
+        &self,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        query_timeout: Duration,
+    ) -> Result<QueryResponse, ClientError> {
+        let name = &name;
+        self.retry(name, query_timeout, || async move {
+            let mut client = self.connector.create_client().await.map_err(|e| {
+                tracing::warn!(
+                    "Unable to establish connection: {:?}, <{}>",
+                    e,
+                    self.dns_name
+                );
+                ClientError::Connect(e)
+            })?;
+            let start = Instant::now();
+            let response = self
+                .send_query(&mut client, name.clone(), query_class, query_type)
+                .await
+                .map_err(|e| {
+                    tracing::warn!("Query failed for <{}>: {:?}, <{}>", name, e, self.dns_name);
+                    ClientError::Protocol(e)
+                })?;
+            Ok(QueryResponse {
+                response: self.check_size(name, response)?,
+                rtt: start.elapsed(),
+                reconnected: true,
+            })
+        })
+        .await
+    }
+
+    /// Runs `attempt` with the query timeout and retry backoff until it
+    /// succeeds, fails permanently, or the retries are used up
+    async fn retry<F, Fut>(
+        &self,
+        name: &Name,
+        query_timeout: Duration,
+        mut attempt: F,
+    ) -> Result<QueryResponse, ClientError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<QueryResponse, ClientError>>,
+    {
+        let mut retries = 0;
+        loop {
+            let error = match tokio::time::timeout(query_timeout, attempt()).await {
+                Ok(Ok(mut response)) => {
+                    // Retried JSON API queries may have gone over a new HTTP connection
+                    response.reconnected |= retries > 0;
+                    return Ok(response);
+                }
+                // Retrying cannot make a response smaller
+                Ok(Err(e @ ClientError::ResponseTooLarge { .. })) => return Err(e),
+                Ok(Err(e)) => e,
+                Err(_) => {
+                    tracing::warn!("Query timeout for <{}>, <{}>", name, self.dns_name);
+                    ClientError::Timeout(query_timeout)
+                }
+            };
+
+            if retries >= self.options.max_retries {
+                return Err(ClientError::MaxRetries {
+                    retries,
+                    last: Some(Box::new(error)),
+                });
+            }
+            tokio::time::sleep(self.retry_delay(retries)).await;
             retries += 1;
         }
     }
 
+    /// Sends a query like [`ClientHandle::query`], but with the provider's RD bit
+    async fn send_query(
+        &self,
+        client: &mut Client,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+    ) -> Result<DnsResponse, hickory_client::ClientError> {
+        let mut query = Query::query(name, query_type);
+        query.set_query_class(query_class);
+        let mut options = DnsRequestOptions::default();
+        options.use_edns = client.is_using_edns();
+        options.recursion_desired = self.options.recursion_desired;
+        Ok(client.lookup(query, options).first_answer().await?)
+    }
+
+    /// Rejects responses over the configured size limit
+    fn check_size(&self, name: &Name, response: DnsResponse) -> Result<DnsResponse, ClientError> {
+        let size = response.as_buffer().len();
+        if size > self.options.max_response_size {
+            tracing::warn!(
+                "Discarding oversized response for <{}>: {} bytes, <{}>",
+                name,
+                size,
+                self.dns_name
+            );
+            return Err(ClientError::ResponseTooLarge {
+                size,
+                limit: self.options.max_response_size,
+            });
+        }
+        Ok(response)
+    }
+
+    /// Exponential backoff with jitter before the next query attempt
+    fn retry_delay(&self, retries: u32) -> Duration {
+        let delay = QUERY_INITIAL_RETRY_DELAY
+            .saturating_mul(2_u64.saturating_pow(retries))
+            .min(QUERY_MAX_RETRY_DELAY);
+        jittered(delay, self.options.jitter)
+    }
+}
+
+impl Connector {
+    /// Connects over the configured path. With several paths, a probe query is
+    /// sent over each, starting from the one that worked last, until one answers.
+    async fn create_client(&self) -> Result<Client, ProtoError> {
+        if let [path] = &self.paths[..] {
+            return self.connect_path(path).await;
+        }
+
+        let first = self.working_path.load(Ordering::Relaxed);
+        let mut last_error = None;
+        for index in (0..self.paths.len()).map(|i| (first + i) % self.paths.len()) {
+            let path = &self.paths[index];
+            // A failed connection does not depend on the path, so there is no point in trying others
+            let mut client = self.connect_path(path).await?;
+            match tokio::time::timeout(
+                PROBE_TIMEOUT,
+                client.query(Name::root(), DNSClass::IN, RecordType::NS),
+            )
+            .await
+            {
+                Ok(Ok(_)) => {
+                    if index != first {
+                        tracing::info!("Switching to DoH path {}, <{}>", path, self.dns_name);
+                        self.working_path.store(index, Ordering::Relaxed);
+                    }
+                    return Ok(client);
+                }
+                Ok(Err(e)) => {
+                    tracing::warn!(
+                        "Probe query over DoH path {} failed: {}, <{}>",
+                        path,
+                        e,
+                        self.dns_name
+                    );
+                    last_error = Some(ProtoError::from(format!("probe over {path} failed: {e}")));
+                }
+                Err(_) => {
+                    tracing::warn!(
+                        "Probe query over DoH path {} timed out, <{}>",
+                        path,
+                        self.dns_name
+                    );
+                    last_error = Some(ProtoError::from(format!("probe over {path} timed out")));
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| ProtoError::from("no DoH path configured")))
+    }
+
+    async fn connect_path(&self, path: &str) -> Result<Client, ProtoError> {
+        match &self.runtime {
+            Some(runtime) => self.connect(runtime.clone(), path).await,
+            None => self.connect(TokioRuntimeProvider::new(), path).await,
+        }
+    }
+
+    async fn connect<P: RuntimeProvider>(
+        &self,
+        provider: P,
+        path: &str,
+    ) -> Result<Client, ProtoError> {
+        let dns_name = &self.dns_name;
+        tracing::debug!(target: concat!(module_path!(), "::stdout"), "Creating HTTPS connection to {}", dns_name);
+
+        let https_builder =
+            HttpsClientStreamBuilder::with_client_config(self.client_config.clone(), provider);
+        let connect = https_builder.build(self.addr, dns_name.clone(), path.to_string());
+        tracing::debug!(target: concat!(module_path!(), "::stdout"), "Connecting AsyncClient: {}", dns_name);
+        let (client, bg) = Client::connect(connect).await?;
+        tokio::spawn(bg);
+        if self.log_tls_details && tracing::enabled!(tracing::Level::DEBUG) {
+            tokio::spawn(tls_info::log_details(
+                self.addr,
+                self.dns_name.clone(),
+                self.client_config.clone(),
+                self.runtime.clone(),
+            ));
+        }
+        Ok(client)
+    }
+
+    /// When the current connection was established; `None` while disconnected
+    fn connected_at(&self) -> Option<Instant> {
+        let holder = self.client_sender.borrow();
+        holder.client.as_ref().and(holder.created)
+    }
+
+    /// Replaces a working connection that reached its maximum lifetime. The new
+    /// connection is established first, so queries never wait for it; queries
+    /// still in flight finish on the old one. Returns false if connecting failed,
+    /// in which case the old connection is kept.
+    async fn rotate(&self) -> bool {
+        tracing::info!("Rotating connection to <{}>", self.dns_name);
+        match self.create_client().await {
+            Ok(new_client) => {
+                self.client_sender.send_modify(|inner| {
+                    inner.client = Some(new_client);
+                    inner.version += 1;
+                    inner.created = Some(Instant::now());
+                    inner.connect_error = None;
+                });
+                true
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Unable to rotate connection: {:?}, keeping the current one, <{}>",
+                    e,
+                    self.dns_name
+                );
+                false
+            }
+        }
+    }
+
     async fn handle_reconnect(&self) {
-        let mut receiver = self.client.clone();
-        let client_holder = {
-            let borrowed = receiver.borrow_and_update();
-            borrowed.clone()
-        };
-        if client_holder.client.is_some() {
+        if self.client_sender.borrow().client.is_some() {
             return;
         }
 
@@ -198,12 +696,15 @@ impl RetryableClient {
 
         loop {
             tracing::info!("Attempting to reconnect to <{}>", self.dns_name);
-            match Self::create_client(self.addr, &self.dns_name, self.client_config.clone()).await {
+            match self.create_client().await {
                 Ok(new_client) => {
                     self.client_sender.send_if_modified(|inner| {
                         tracing::info!("Established connection with <{}>", self.dns_name);
                         inner.client = Some(new_client);
                         inner.version += 1;
+                        inner.created = Some(Instant::now());
+                        inner.connect_error = None;
+                        inner.connected_once = true;
                         true
                     });
                     return;
@@ -217,10 +718,15 @@ impl RetryableClient {
                     if is_network_unreachable_error(&e) {
                         retry_delay = MAX_RETRY_DELAY;
                     }
+                    // Record the failure for queries without notifying waiters
+                    self.client_sender.send_if_modified(|inner| {
+                        inner.connect_error = Some(e);
+                        false
+                    });
                 }
             }
 
-            tokio::time::sleep(Duration::from_millis(retry_delay)).await;
+            tokio::time::sleep(jittered(retry_delay, self.jitter)).await;
             retry_delay = retry_delay.saturating_mul(2).min(MAX_RETRY_DELAY);
             retry_count += 1;
             if retry_count >= MAX_RETRIES {
@@ -231,15 +737,29 @@ impl RetryableClient {
     }
 }
 
-fn is_network_unreachable_error(e: &anyhow::Error) -> bool {
-    e.downcast_ref::<std::io::Error>().is_some_and(|e| {
-        if e.raw_os_error() == Some(51) {
-            // 51 = ENETUNREACH on Unix
-            return true;
-        }
-        if e.kind() == std::io::ErrorKind::UnexpectedEof {
-            return true;
-        }
-        false
-    })
+/// Applies jitter to a backoff delay: the randomized `jitter` fraction of the
+/// delay is replaced by a uniform random value between zero and that fraction
+fn jittered(delay_ms: u64, jitter: f64) -> Duration {
+    let jitter = jitter.clamp(0.0, 1.0);
+    let random_part = (delay_ms as f64 * jitter) as u64;
+    let random = if random_part > 0 {
+        rand::random_range(0..=random_part)
+    } else {
+        0
+    };
+    Duration::from_millis(delay_ms - random_part + random)
+}
+
+fn is_network_unreachable_error(e: &ProtoError) -> bool {
+    let ProtoErrorKind::Io(e) = e.kind() else {
+        return false;
+    };
+    if e.raw_os_error() == Some(51) {
+        // 51 = ENETUNREACH on Unix
+        return true;
+    }
+    if e.kind() == std::io::ErrorKind::UnexpectedEof {
+        return true;
+    }
+    false
 }
@@ -1,68 +1,1297 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
+use futures::StreamExt;
+use hickory_proto::{
+    rr::{DNSClass, Name, RData, Record, RecordType},
+    serialize::txt::RDataParser,
+};
 use serde::Deserialize;
-use std::{collections::HashMap, net::SocketAddr, str::FromStr};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::io::AsyncReadExt;
+
+use crate::runtime::MAX_DSCP;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Environment variable with providers as a JSON object keyed by provider name,
+/// merged over the providers of the configuration file
+pub const PROVIDERS_ENV: &str = "RACE_DNS_PROXY_PROVIDERS";
+
+/// Domain lists as last loaded successfully, by source, to fall back on when
+/// loading one fails on a later reload
+static LOADED_LISTS: LazyLock<Mutex<HashMap<String, Vec<String>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 
 pub type DomainRules = (Vec<String>, Vec<String>);
-pub type ProviderInfo = (SocketAddr, String, String, DomainRules);
+
+pub struct ProviderInfo {
+    pub addr: SocketAddr,
+    pub hostname: String,
+    pub name: String,
+    pub domain_rules: DomainRules,
+    pub drop_types: Vec<RecordType>,
+    pub authoritative_negative: bool,
+    pub timeout: Option<Duration>,
+    pub tier: u8,
+    pub priority: u8,
+    pub query_classes: Vec<DNSClass>,
+    /// URL path of the JSON DoH API, set for providers with `api = "json"`
+    pub json_path: Option<String>,
+    pub reuse: bool,
+    pub monitor: bool,
+    pub dscp: Option<u8>,
+    pub netns: Option<String>,
+    pub max_connection_lifetime: Option<Duration>,
+    /// URL paths of the wire format endpoint, tried in order
+    pub paths: Vec<String>,
+    pub recursion_desired: bool,
+    pub log: bool,
+}
+
+pub type GroupInfo = (String, DomainRules, GroupOptions);
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// May be left out when the providers come from [`PROVIDERS_ENV`]
+    #[serde(default)]
     pub providers: HashMap<String, Provider>,
+    /// Names of the providers read from [`PROVIDERS_ENV`], and whether each
+    /// replaced a provider of the configuration file
+    #[serde(skip)]
+    pub env_providers: Vec<(String, bool)>,
+    #[serde(default)]
+    pub domain_groups: HashMap<String, DomainGroup>,
+    #[serde(default)]
+    pub rebind_protection: RebindProtection,
+    #[serde(default)]
+    pub bypass: BypassConfig,
+    #[serde(default)]
+    pub race: RaceConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default, rename = "config")]
+    pub reload: ReloadConfig,
+    #[serde(default)]
+    pub startup: StartupConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub listen: ListenConfig,
+    #[serde(default)]
+    pub response: ResponseConfig,
+    #[serde(default)]
+    pub chaos: ChaosConfig,
+    #[serde(default)]
+    pub special_use: SpecialUseConfig,
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    #[serde(default)]
+    pub routing: RoutingConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    #[serde(default)]
+    pub root: RootConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// Blocked domains, keyed by blocklist name
+    #[serde(default)]
+    pub blocklists: HashMap<String, Blocklist>,
+    /// Zone files answered authoritatively, keyed by zone name
+    #[serde(default)]
+    pub local_zones: HashMap<String, String>,
+    /// Fixed records answered locally
+    #[serde(default)]
+    pub local_records: Vec<LocalRecord>,
+}
+
+/// A record answered without racing upstream; several may share a name
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalRecord {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    /// Record data in zone file syntax, e.g. `"10 mail.example.com."` for MX
+    pub value: String,
+    #[serde(default = "default_local_record_ttl")]
+    pub ttl: u32,
+}
+
+fn default_local_record_ttl() -> u32 {
+    300
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenConfig {
+    /// Path of a Unix stream socket to serve DNS on, in addition to UDP/TCP
     #[serde(default)]
-    pub domain_groups: HashMap<String, Vec<String>>,
+    pub unix: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// Fraction of successful queries logged at INFO level
+    pub sample_rate: f64,
+    /// How the `--log` file is rotated
+    pub rotation: LogRotation,
+    /// Size in bytes at which the log file is rotated with `rotation = "size"`
+    pub max_size: u64,
+    /// Number of rotated log files kept with `rotation = "size"`
+    pub max_files: usize,
+    /// File receiving one access log line per query, rotated like the `--log` file
+    pub access_log: Option<String>,
+    /// Truncate client addresses in the access log to /24 (IPv4) or /56 (IPv6)
+    pub access_log_anonymize: bool,
+    /// Log each upstream's TLS version, cipher suite, ALPN and certificate at debug level
+    pub tls_details: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 1.0,
+            rotation: LogRotation::default(),
+            max_size: 100 * 1024 * 1024,
+            max_files: 5,
+            access_log: None,
+            access_log_anonymize: true,
+            tls_details: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    /// Start a new file every day
+    #[default]
+    Daily,
+    /// Start a new file once the current one reaches `max_size`
+    Size,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseConfig {
+    /// Strip the authority and additional sections, sending only answers
+    #[serde(default)]
+    pub minimal: bool,
+    /// When to name the answer's source (winning provider, cache, ...) in an EDNS option
+    #[serde(default)]
+    pub debug_provider: DebugProvider,
+    /// How queries without the RD (recursion desired) bit are answered
+    #[serde(default)]
+    pub non_recursive: NonRecursive,
+    /// Pad responses to clients that send the EDNS padding option (RFC 7830) to a
+    /// multiple of this many bytes
+    #[serde(default)]
+    pub padding_block_size: Option<u16>,
+    /// Also pad responses sent over unencrypted transports
+    #[serde(default)]
+    pub pad_unencrypted: bool,
+    /// Sort the answers of each name by record type and data instead of keeping
+    /// the upstream order
+    #[serde(default)]
+    pub sort_answers: bool,
+    /// Largest UDP response in bytes, on top of the payload size the client advertises
+    #[serde(default)]
+    pub max_udp_size: Option<u16>,
+    /// Largest response in bytes over TCP and the Unix socket
+    #[serde(default)]
+    pub max_tcp_size: Option<u16>,
+}
+
+/// How non-recursive (RD=0) queries are answered. Local zones, local records and
+/// blocklists answer them either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NonRecursive {
+    /// Race them like recursive queries
+    #[default]
+    Forward,
+    /// Answer from the cache, and REFUSED on a cache miss
+    CacheOnly,
+    /// Answer REFUSED
+    Refuse,
+}
+
+/// When responses carry the debug EDNS option naming the answer's source
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DebugProvider {
+    /// Never, so upstream identities are not disclosed
+    #[default]
+    Off,
+    /// Only when the query carries the debug option itself
+    OnRequest,
+    /// In every response to an EDNS query
+    Always,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Root certificates that upstream certificates are verified against
+    pub roots: TlsRoots,
+}
+
+/// Source of the trusted root certificates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsRoots {
+    /// Mozilla's root certificates, bundled with the binary
+    #[default]
+    Webpki,
+    /// The operating system's trust store
+    System,
+}
+
+/// Answers to the CHAOS class identification queries (RFC 4892)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct ChaosConfig {
+    /// Answer to `version.bind` and `version.server`
+    pub version: String,
+    /// Answer to `hostname.bind` and `id.server`
+    pub hostname: String,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            version: "unknown".to_string(),
+            hostname: "unknown".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct RootConfig {
+    /// How queries for the root name (`.`) are answered
+    pub mode: RootMode,
+    /// Root hints file (e.g. named.root) answering root queries in `hints` mode
+    pub hints: Option<String>,
+}
+
+/// How queries for the root name are answered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RootMode {
+    /// Race them like any other query
+    #[default]
+    Forward,
+    /// Answer from the root hints file when it has records of the queried type,
+    /// and race the others
+    Hints,
+    /// Answer REFUSED
+    Refuse,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct StatsConfig {
+    /// Log the query, cache and provider counters at this interval; off if unset
+    pub log_interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingConfig {
+    /// Which pattern decides when a name matches both an include and an exclude pattern
+    pub conflict: RuleConflict,
+}
+
+/// How a name matching both an include and an exclude pattern of the same rules is routed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleConflict {
+    /// The exclude pattern wins
+    #[default]
+    ExcludeWins,
+    /// The longer, more specific pattern wins; the exclude pattern wins a tie
+    LongestMatch,
+}
+
+/// Special-use names (RFC 6761) answered locally instead of being raced
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct SpecialUseConfig {
+    /// Answer `localhost` and the names below it with the loopback addresses
+    pub localhost: bool,
+    /// Further names, including their subdomains, answered with NXDOMAIN
+    pub names: Vec<String>,
+}
+
+impl Default for SpecialUseConfig {
+    fn default() -> Self {
+        Self {
+            localhost: true,
+            names: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Fraction of each retry/reconnect backoff delay that is randomized
+    pub jitter: f64,
+    /// Retries of a failed upstream query; 0 returns the first error immediately
+    pub max_retries: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            jitter: 1.0,
+            max_retries: 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct StartupConfig {
+    /// Delay accepting queries until at least one provider has connected
+    pub wait_for_first_connection: bool,
+    /// Upper bound for `wait_for_first_connection`
+    pub wait_timeout_ms: u64,
+    /// Answer SERVFAIL immediately while none of the selected providers has connected yet
+    pub fail_fast: bool,
+    /// Domain list files and URLs loaded at the same time
+    pub fetch_concurrency: usize,
+    /// Upper bound for loading each domain list
+    pub fetch_timeout_ms: u64,
+}
+
+impl Default for StartupConfig {
+    fn default() -> Self {
+        Self {
+            wait_for_first_connection: false,
+            wait_timeout_ms: 5000,
+            fail_fast: false,
+            fetch_concurrency: 4,
+            fetch_timeout_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct ReloadConfig {
+    /// Watch the configuration file and reload it when it changes
+    pub watch: bool,
+    /// Quiet period after the last change before reloading
+    pub debounce_ms: u64,
+    /// How long the running configuration keeps answering after a reload while
+    /// the providers of the new one connect
+    pub connect_wait_ms: u64,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        Self {
+            watch: false,
+            debounce_ms: 500,
+            connect_wait_ms: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Upstream responses larger than this many bytes lose the race
+    pub max_response_size: usize,
+    /// UDP queries repeating the source, ID and name of a query still being
+    /// answered within this many milliseconds are dropped; 0 disables
+    pub retransmit_window_ms: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_response_size: 65535,
+            retransmit_window_ms: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Cache NXDOMAIN responses from providers marked `authoritative_negative`
+    pub negative: bool,
+    /// Cache winning answers for the lowest TTL among their records
+    pub positive: bool,
+    pub max_entries: usize,
+    /// Upper bound in seconds on how long NXDOMAIN answers are cached,
+    /// whatever their SOA advertises
+    pub max_negative_ttl: u64,
+    /// Lower bound in seconds on the TTLs reported in answers served from the
+    /// cache, so entries close to expiring are never served with TTL 0
+    pub min_served_ttl: Option<u32>,
+    /// Upper bound in seconds on the TTLs reported in answers served from the cache
+    pub max_served_ttl: Option<u32>,
+    /// Entries served within this many seconds of expiring are refreshed in the background
+    pub prefetch_window_secs: Option<u64>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            negative: false,
+            positive: false,
+            max_entries: 10000,
+            max_negative_ttl: 3600,
+            min_served_ttl: None,
+            max_served_ttl: None,
+            prefetch_window_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct RaceConfig {
+    /// Log a one-line summary of every provider's result after each race
+    pub summary: bool,
+    /// How long to keep collecting results for the summary after the winner was sent
+    pub summary_wait_ms: u64,
+    /// Which response wins the race
+    pub winner: WinnerPolicy,
+    /// Consecutive SERVFAILs for a query after which a provider is raced last
+    /// for that query; 0 disables
+    pub servfail_threshold: u32,
+    /// How long a provider stays deprioritized after its last SERVFAIL
+    pub servfail_memory_ms: u64,
+    /// Prefer answers with the AD (authenticated data) bit over those without
+    pub prefer_authenticated: bool,
+    /// How long an unauthenticated winner is held back waiting for an authenticated one
+    pub authenticated_wait_ms: u64,
+    /// Query one provider chosen by a hash of the query name first, and only
+    /// race the others when it fails
+    pub sticky: bool,
+    /// Receiver of every race's outcome
+    pub observer: ObserverKind,
+    /// Whether providers are raced or tried one after another
+    pub mode: RaceMode,
+    /// Upper bound on the time until an answer is sent, across all tiers and
+    /// retries; when it runs out, the best response so far or SERVFAIL is sent
+    pub total_timeout_ms: Option<u64>,
+    /// Upper bound on how long the adaptive mode delays a provider
+    pub adaptive_max_delay_ms: u64,
+}
+
+impl Default for RaceConfig {
+    fn default() -> Self {
+        Self {
+            summary: false,
+            summary_wait_ms: 1000,
+            winner: WinnerPolicy::default(),
+            servfail_threshold: 0,
+            servfail_memory_ms: 30_000,
+            prefer_authenticated: false,
+            authenticated_wait_ms: 100,
+            sticky: false,
+            observer: ObserverKind::default(),
+            mode: RaceMode::default(),
+            total_timeout_ms: None,
+            adaptive_max_delay_ms: 200,
+        }
+    }
+}
+
+/// How the providers matching a query are queried
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RaceMode {
+    /// Query all providers of a tier at once
+    #[default]
+    Race,
+    /// Query one provider at a time, by tier, priority and name, until one gives a winning answer
+    Failover,
+    /// Query the provider with the lowest average latency first and add the others
+    /// one by one, each once the average latency of the one before it has passed
+    Adaptive,
+}
+
+/// Built-in [`QueryObserver`](crate::observer::QueryObserver) implementations
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObserverKind {
+    /// Race outcomes are not observed
+    #[default]
+    None,
+    /// Log every race outcome
+    Log,
+}
+
+/// How the winning response of a race is chosen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WinnerPolicy {
+    /// The first response that is neither SERVFAIL nor NXDOMAIN
+    #[default]
+    FirstOk,
+    /// The first such response that also carries answer records
+    FirstWithAnswers,
+    /// Wait for every provider, then pick the fastest response with answer records
+    LowestLatencyWithAnswers,
+    /// Wait for every provider, then answer with the union of all answer records
+    MergeAnswers,
+}
+
+impl WinnerPolicy {
+    /// Whether the race must finish before a winner can be chosen
+    pub fn waits_for_all(self) -> bool {
+        matches!(self, Self::LowestLatencyWithAnswers | Self::MergeAnswers)
+    }
+}
+
+/// DNS rebinding protection: reject private/loopback answers for public names
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RebindProtection {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Zones whose names may legitimately resolve to private addresses
+    #[serde(default)]
+    pub allow_zones: Vec<String>,
+}
+
+/// Domains that are always raced fresh, e.g. for captive portal detection or OCSP
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BypassConfig {
+    /// Domains, including their subdomains, that skip the cache and blocklists
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+/// Domains answered locally with a fixed response instead of being raced
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Blocklist {
+    /// Blocked domains, including their subdomains
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub action: BlockAction,
+    /// Addresses returned by the `custom_ip` action
+    #[serde(default)]
+    pub custom_ip: Vec<IpAddr>,
+    /// TTL of synthesized address records
+    #[serde(default = "default_block_ttl")]
+    pub ttl: u32,
+}
+
+fn default_block_ttl() -> u32 {
+    60
+}
+
+/// How queries for blocked domains are answered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockAction {
+    /// NXDOMAIN: the name does not exist
+    #[default]
+    Nxdomain,
+    /// NOERROR without answers
+    Nodata,
+    /// `0.0.0.0` for A queries and `::` for AAAA queries
+    ZeroIp,
+    /// The addresses in `custom_ip`, e.g. a block page server
+    CustomIp,
+    /// REFUSED
+    Refused,
+    /// SERVFAIL
+    Servfail,
+}
+
+/// Answers every query with a fixed response instead of racing it, e.g. during
+/// planned maintenance
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    pub enabled: bool,
+    pub action: BlockAction,
+    /// Addresses returned by the `custom_ip` action, e.g. a status page server
+    pub custom_ip: Vec<IpAddr>,
+    /// TTL of synthesized address records
+    pub ttl: u32,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            action: BlockAction::Servfail,
+            custom_ip: Vec::new(),
+            ttl: default_block_ttl(),
+        }
+    }
+}
+
+/// A domain group is either a plain list of domains or a table with
+/// `domains` and/or a domain list `file` plus per-group options.
+#[derive(Debug, Deserialize)]
+#[serde(from = "DomainGroupRepr")]
+pub struct DomainGroup {
+    pub domains: Vec<String>,
+    /// File or HTTP(S) URL with one domain per line, gzip-compressed if it ends in `.gz`
+    pub file: Option<String>,
+    pub options: GroupOptions,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GroupOptions {
+    /// Randomize the order of answer records of the queried type
+    #[serde(default)]
+    pub shuffle_answers: bool,
+    /// Timeout for each query attempt, overriding the providers' own timeouts
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Fewest answer records of the queried type a winning response must have, by
+    /// record type; responses with fewer cannot win but remain fallback candidates
+    #[serde(default)]
+    pub min_answers: HashMap<String, usize>,
+}
+
+impl GroupOptions {
+    /// The minimum number of answers for a query type; 0 if none is configured
+    pub fn min_answers(&self, query_type: RecordType) -> usize {
+        self.min_answers
+            .get(&query_type.to_string())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DomainGroupRepr {
+    Domains(Vec<String>),
+    Table(DomainGroupTable),
+}
+
+#[derive(Deserialize)]
+struct DomainGroupTable {
+    #[serde(default)]
+    domains: Vec<String>,
+    #[serde(default)]
+    file: Option<String>,
+    #[serde(flatten)]
+    options: GroupOptions,
+}
+
+impl From<DomainGroupRepr> for DomainGroup {
+    fn from(repr: DomainGroupRepr) -> Self {
+        match repr {
+            DomainGroupRepr::Domains(domains) => Self {
+                domains,
+                file: None,
+                options: GroupOptions::default(),
+            },
+            DomainGroupRepr::Table(table) => Self {
+                domains: table.domains,
+                file: table.file,
+                options: table.options,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Provider {
     pub addr: String,
     pub hostname: String,
     #[serde(default)]
     pub domain_groups: Vec<String>,
+    /// Record types stripped from this provider's responses
+    #[serde(default)]
+    pub drop_types: Vec<String>,
+    /// Whether this provider's NXDOMAIN answers may be cached
+    #[serde(default)]
+    pub authoritative_negative: bool,
+    /// Timeout for each query attempt, overriding the built-in default
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Providers of higher tiers are only raced when every lower tier failed
+    #[serde(default)]
+    pub tier: u8,
+    /// Order within a tier in failover mode; lower is tried first, ties by name
+    #[serde(default)]
+    pub priority: u8,
+    /// Query classes this provider serves; empty means all classes
+    #[serde(default)]
+    pub query_classes: Vec<String>,
+    /// Which DoH API the provider is queried with
+    #[serde(default)]
+    pub api: ProviderApi,
+    /// URL path of the JSON API
+    #[serde(default = "default_json_path")]
+    pub json_path: String,
+    /// Keep a long-lived connection; false opens a new connection per query
+    #[serde(default = "default_reuse")]
+    pub reuse: bool,
+    /// Query this provider for logging only; its answers are never used
+    #[serde(default)]
+    pub monitor: bool,
+    /// DSCP value (0-63) marked on the connections to this provider
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// Network namespace, by name or path, to open the connections to this provider in (Linux)
+    #[serde(default)]
+    pub netns: Option<String>,
+    /// Replace the connection once it is this many seconds old, even if it still works
+    #[serde(default)]
+    pub max_connection_lifetime_secs: Option<u64>,
+    /// URL path of the wire format endpoint, or several paths tried in order
+    #[serde(default)]
+    pub path: Option<DohPaths>,
+    /// Set the RD bit on queries; clear it for upstreams in front of authoritative servers
+    #[serde(default = "default_recursion_desired")]
+    pub recursion_desired: bool,
+    /// Log this provider's answers to each query; failures are logged regardless
+    #[serde(default = "default_log")]
+    pub log: bool,
+}
+
+/// One DoH path or a list of them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum DohPaths {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// The DoH API used to query a provider
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderApi {
+    /// RFC 8484 wire format
+    #[default]
+    Wire,
+    /// JSON API (`application/dns-json`), as offered by e.g. Google at `/resolve`
+    Json,
+}
+
+/// URL path of the RFC 8484 endpoint (RFC 8484 section 4.1.1 suggests it)
+pub const DEFAULT_DOH_PATH: &str = "/dns-query";
+
+fn default_json_path() -> String {
+    "/resolve".to_string()
+}
+
+fn default_reuse() -> bool {
+    true
+}
+
+fn default_recursion_desired() -> bool {
+    true
+}
+
+fn default_log() -> bool {
+    true
 }
 
 impl Config {
-    pub fn load(filepath: &str) -> Result<Self> {
-        let config_str = std::fs::read_to_string(filepath)?;
-        let config: Config = toml::from_str(&config_str)?;
+    /// Logs the providers read from [`PROVIDERS_ENV`]. At startup, `load` runs
+    /// before the logger is set up, so it leaves this to its caller.
+    pub fn log_env_providers(&self) {
+        if self.env_providers.is_empty() {
+            return;
+        }
+        tracing::info!(
+            "Loaded {} provider(s) from {}: {}",
+            self.env_providers.len(),
+            PROVIDERS_ENV,
+            self.env_providers
+                .iter()
+                .map(|(name, overrides)| if *overrides {
+                    format!("{name} (replacing the file's)")
+                } else {
+                    name.clone()
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    /// Loads the configuration from a file path, `-` for stdin, or an HTTP(S) URL
+    pub async fn load(source: &str) -> Result<Self> {
+        let config_str = read_source(source).await?;
+        let mut config: Config = toml::from_str(&config_str)
+            .map_err(|e| anyhow::anyhow!(describe_toml_error(source, &config_str, &e)))?;
+        // A provider from the environment replaces the file's provider of the same name
+        for (name, provider) in env_providers()? {
+            let overrides = config.providers.insert(name.clone(), provider).is_some();
+            config.env_providers.push((name, overrides));
+        }
+        config.env_providers.sort();
+        if config.providers.is_empty() {
+            bail!("No providers configured: add [providers] to {source} or set {PROVIDERS_ENV}");
+        }
+        // Also rejects NaN, which TOML accepts
+        if !(0.0..=1.0).contains(&config.log.sample_rate) {
+            bail!("sample_rate in [log] must be between 0.0 and 1.0");
+        }
+        if config.race.total_timeout_ms == Some(0) {
+            bail!("total_timeout_ms in [race] must be greater than 0");
+        }
+        if let (Some(min), Some(max)) = (config.cache.min_served_ttl, config.cache.max_served_ttl)
+            && min > max
+        {
+            bail!("min_served_ttl in [cache] must not exceed max_served_ttl");
+        }
+        if config.root.mode == RootMode::Hints && config.root.hints.is_none() {
+            bail!("mode = \"hints\" in [root] requires a hints file");
+        }
+        if config.stats.log_interval_secs == Some(0) {
+            bail!("log_interval_secs in [stats] must be greater than 0");
+        }
+        for (name, provider) in &config.providers {
+            if provider.timeout_ms == Some(0) {
+                bail!("timeout_ms of provider {name} must be greater than 0");
+            }
+        }
+        for (name, group) in &config.domain_groups {
+            if group.options.timeout_ms == Some(0) {
+                bail!("timeout_ms of domain group {name} must be greater than 0");
+            }
+        }
+        for (key, size) in [
+            ("max_udp_size", config.response.max_udp_size),
+            ("max_tcp_size", config.response.max_tcp_size),
+        ] {
+            if size.is_some_and(|size| size < 512) {
+                bail!("{key} in [response] must be at least 512");
+            }
+        }
+        config.load_domain_lists().await?;
+        // Record types are matched by their canonical name
+        for (name, group) in &mut config.domain_groups {
+            group.options.min_answers = group
+                .options
+                .min_answers
+                .iter()
+                .map(|(record_type, &count)| {
+                    let parsed = RecordType::from_str(&record_type.to_uppercase())
+                        .with_context(|| {
+                            format!("Invalid record type {record_type:?} in min_answers of domain group {name}")
+                        })?;
+                    Ok((parsed.to_string(), count))
+                })
+                .collect::<Result<_>>()?;
+        }
         Ok(config)
     }
 
+    /// Adds the domains of every group's `file` to the group, loading at most
+    /// `fetch_concurrency` lists at a time
+    async fn load_domain_lists(&mut self) -> Result<()> {
+        let sources: Vec<(String, String)> = self
+            .domain_groups
+            .iter()
+            .filter_map(|(name, group)| Some((name.clone(), group.file.clone()?)))
+            .collect();
+        let client = reqwest::Client::new();
+        let timeout = Duration::from_millis(self.startup.fetch_timeout_ms);
+        let lists: Vec<_> = futures::stream::iter(sources.clone())
+            .map(|(name, source)| {
+                let client = client.clone();
+                async move {
+                    let domains = load_domain_list(&client, &source, timeout).await;
+                    (name, domains)
+                }
+            })
+            .buffer_unordered(self.startup.fetch_concurrency.max(1))
+            .collect()
+            .await;
+
+        for (name, domains) in lists {
+            let domains = domains.with_context(|| format!("Failed to load domain group {name}"))?;
+            if let Some(group) = self.domain_groups.get_mut(&name) {
+                group.domains.extend(domains);
+            }
+        }
+        // Forget the lists of groups that were removed
+        LOADED_LISTS
+            .lock()
+            .unwrap()
+            .retain(|source, _| sources.iter().any(|(_, s)| s == source));
+        Ok(())
+    }
+
     pub fn get_providers(&self) -> Result<Vec<ProviderInfo>> {
         let mut providers = Vec::new();
         for (key, provider) in &self.providers {
-            let addr = SocketAddr::from_str(&provider.addr)?;
+            let addr = SocketAddr::from_str(&provider.addr).map_err(|_| {
+                let hint = match IpAddr::from_str(&provider.addr) {
+                    Ok(ip) => format!("the port is missing, e.g. \"{}\"", SocketAddr::new(ip, 443)),
+                    Err(_) => "expected an IP address and port, e.g. \"1.1.1.1:443\"".to_string(),
+                };
+                anyhow::anyhow!("Invalid addr {:?} of provider {key}: {hint}", provider.addr)
+            })?;
 
             let mut includes = Vec::new();
             let mut excludes = Vec::new();
 
             for group_name in &provider.domain_groups {
-                if let Some(group_domains) = self.domain_groups.get(group_name) {
-                    for domain in group_domains {
-                        if let Some(stripped_domain) = domain.strip_prefix('!') {
-                            excludes.push(stripped_domain.to_string());
-                        } else {
-                            includes.push(domain.clone());
-                        }
-                    }
+                if let Some(group) = self.domain_groups.get(group_name) {
+                    split_domains(&group.domains, &mut includes, &mut excludes);
                 }
             }
 
             if provider.domain_groups.iter().any(|g| {
                 self.domain_groups
                     .get(g)
-                    .is_some_and(|domains| domains.is_empty())
+                    .is_some_and(|group| group.domains.is_empty())
             }) {
                 includes.clear();
                 excludes.clear();
             }
 
-            providers.push((
+            let drop_types = provider
+                .drop_types
+                .iter()
+                .map(|t| {
+                    RecordType::from_str(&t.to_uppercase())
+                        .with_context(|| format!("Invalid record type {t:?} in provider {key}"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let query_classes = provider
+                .query_classes
+                .iter()
+                .map(|c| {
+                    DNSClass::from_str(&c.to_uppercase())
+                        .with_context(|| format!("Invalid query class {c:?} in provider {key}"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            if let Some(dscp) = provider.dscp {
+                if dscp > MAX_DSCP {
+                    bail!(
+                        "Invalid dscp {dscp} of provider {key}: expected a value from 0 to {MAX_DSCP}"
+                    );
+                }
+                if provider.api == ProviderApi::Json {
+                    bail!("Provider {key} sets dscp, which is not supported with api = \"json\"");
+                }
+            }
+
+            if provider.netns.is_some() {
+                if !cfg!(target_os = "linux") {
+                    bail!("Provider {key} sets netns, which is only supported on Linux");
+                }
+                if provider.api == ProviderApi::Json {
+                    bail!("Provider {key} sets netns, which is not supported with api = \"json\"");
+                }
+            }
+
+            if let Some(lifetime) = provider.max_connection_lifetime_secs {
+                if lifetime == 0 {
+                    bail!(
+                        "Invalid max_connection_lifetime_secs of provider {key}: must be at least 1"
+                    );
+                }
+                if provider.api == ProviderApi::Json || !provider.reuse {
+                    bail!(
+                        "Provider {key} sets max_connection_lifetime_secs, which requires reuse = true and api = \"wire\""
+                    );
+                }
+            }
+
+            let paths = match &provider.path {
+                None => vec![DEFAULT_DOH_PATH.to_string()],
+                Some(DohPaths::One(path)) => vec![path.clone()],
+                Some(DohPaths::Many(paths)) => paths.clone(),
+            };
+            if paths.is_empty() {
+                bail!("Provider {key} has an empty path list");
+            }
+            if let Some(path) = paths.iter().find(|path| !path.starts_with('/')) {
+                bail!("Invalid path {path:?} of provider {key}: must start with \"/\"");
+            }
+            if !provider.recursion_desired && provider.api == ProviderApi::Json {
+                bail!(
+                    "Provider {key} clears recursion_desired, which is not supported with api = \"json\""
+                );
+            }
+            if provider.path.is_some() && provider.api == ProviderApi::Json {
+                bail!(
+                    "Provider {key} sets path, which is not used with api = \"json\"; set json_path instead"
+                );
+            }
+
+            providers.push(ProviderInfo {
                 addr,
-                provider.hostname.clone(),
-                key.clone(),
-                (includes, excludes),
-            ));
+                hostname: provider.hostname.clone(),
+                name: key.clone(),
+                domain_rules: (includes, excludes),
+                drop_types,
+                authoritative_negative: provider.authoritative_negative,
+                timeout: provider.timeout_ms.map(Duration::from_millis),
+                tier: provider.tier,
+                priority: provider.priority,
+                query_classes,
+                reuse: provider.reuse,
+                monitor: provider.monitor,
+                dscp: provider.dscp,
+                netns: provider.netns.clone(),
+                max_connection_lifetime: provider
+                    .max_connection_lifetime_secs
+                    .map(Duration::from_secs),
+                paths,
+                recursion_desired: provider.recursion_desired,
+                log: provider.log,
+                json_path: (provider.api == ProviderApi::Json).then(|| provider.json_path.clone()),
+            });
         }
         Ok(providers)
     }
+
+    /// Parses the local records
+    pub fn get_local_records(&self) -> Result<Vec<Record>> {
+        self.local_records
+            .iter()
+            .map(|record| {
+                let mut name = Name::from_str(&record.name)
+                    .with_context(|| format!("Invalid local record name {:?}", record.name))?;
+                name.set_fqdn(true);
+                let record_type = RecordType::from_str(&record.record_type.to_uppercase())
+                    .with_context(|| {
+                        format!(
+                            "Invalid record type {:?} of local record {}",
+                            record.record_type, record.name
+                        )
+                    })?;
+                let rdata = RData::try_from_str(record_type, &record.value).with_context(|| {
+                    format!(
+                        "Invalid {} value {:?} of local record {}",
+                        record_type, record.value, record.name
+                    )
+                })?;
+                Ok(Record::from_rdata(name, record.ttl, rdata))
+            })
+            .collect()
+    }
+
+    /// Returns every domain group with its rules and options, sorted by name
+    pub fn get_groups(&self) -> Vec<GroupInfo> {
+        let mut groups: Vec<_> = self
+            .domain_groups
+            .iter()
+            .map(|(name, group)| {
+                let mut includes = Vec::new();
+                let mut excludes = Vec::new();
+                split_domains(&group.domains, &mut includes, &mut excludes);
+                (name.clone(), (includes, excludes), group.options.clone())
+            })
+            .collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+        groups
+    }
+}
+
+/// Loads a domain list within `timeout`. If that fails, the list is taken from
+/// the previous load of the configuration, if there was one.
+async fn load_domain_list(
+    client: &reqwest::Client,
+    source: &str,
+    timeout: Duration,
+) -> Result<Vec<String>> {
+    let result = match tokio::time::timeout(timeout, read_domain_list(client, source)).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow::anyhow!(
+            "Loading {source} timed out after {timeout:?}"
+        )),
+    };
+    let mut loaded = LOADED_LISTS.lock().unwrap();
+    match result {
+        Ok(domains) => {
+            loaded.insert(source.to_string(), domains.clone());
+            Ok(domains)
+        }
+        Err(e) => match loaded.get(source) {
+            Some(domains) => {
+                tracing::warn!(
+                    "{:#}, keeping the {} domains loaded before",
+                    e,
+                    domains.len()
+                );
+                Ok(domains.clone())
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Reads a domain list from a file or an HTTP(S) URL, with one domain per line,
+/// skipping blank lines and `#` comments. Sources ending in `.gz` are decompressed first.
+async fn read_domain_list(client: &reqwest::Client, source: &str) -> Result<Vec<String>> {
+    use std::io::Read;
+
+    let bytes = if source.starts_with("http://") || source.starts_with("https://") {
+        client
+            .get(source)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to fetch {source}"))?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to read body of {source}"))?
+            .to_vec()
+    } else {
+        tokio::fs::read(source)
+            .await
+            .with_context(|| format!("Failed to read {source}"))?
+    };
+    let path = source.split(['?', '#']).next().unwrap_or_default();
+    let contents = if path.ends_with(".gz") {
+        let mut contents = String::new();
+        flate2::read::GzDecoder::new(&bytes[..])
+            .read_to_string(&mut contents)
+            .with_context(|| format!("Failed to decompress {source}"))?;
+        contents
+    } else {
+        String::from_utf8(bytes).with_context(|| format!("{source} is not valid UTF-8"))?
+    };
+
+    let domains: Vec<String> = contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    // An empty group would match every domain
+    if domains.is_empty() {
+        bail!("{source} contains no domains");
+    }
+    Ok(domains)
+}
+
+/// Whether a config source is a local file (as opposed to stdin or a URL)
+pub fn is_file_source(source: &str) -> bool {
+    source != "-" && !source.starts_with("http://") && !source.starts_with("https://")
+}
+
+/// Reads the providers from [`PROVIDERS_ENV`]; none if it is unset or blank
+fn env_providers() -> Result<HashMap<String, Provider>> {
+    let json = match std::env::var(PROVIDERS_ENV) {
+        Ok(json) if !json.trim().is_empty() => json,
+        Ok(_) | Err(std::env::VarError::NotPresent) => return Ok(HashMap::new()),
+        Err(std::env::VarError::NotUnicode(_)) => bail!("{PROVIDERS_ENV} is not valid UTF-8"),
+    };
+    let providers: HashMap<String, Provider> = serde_json::from_str(&json).map_err(|e| {
+        anyhow::anyhow!(
+            "Invalid {PROVIDERS_ENV}: {e}; expected a JSON object of providers keyed by name, \
+             e.g. {{\"cloudflare\": {{\"addr\": \"1.1.1.1:443\", \"hostname\": \"cloudflare-dns.com\"}}}}"
+        )
+    })?;
+    Ok(providers)
+}
+
+/// Formats a TOML error with the configuration source, line and column, the
+/// offending line, and a hint for common mistakes
+fn describe_toml_error(source: &str, config_str: &str, error: &toml::de::Error) -> String {
+    let message = error.message().trim_end();
+    let Some(span) = error.span() else {
+        return format!("Invalid configuration {source}: {message}");
+    };
+
+    let before = &config_str[..span.start];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+    let line_text = config_str[line_start..].lines().next().unwrap_or_default();
+
+    let mut description =
+        format!("Invalid configuration {source} at line {line}, column {column}: {message}");
+    if let Some((key, _)) = line_text.split_once('=') {
+        description.push_str(&format!(" (key `{}`)", key.trim()));
+    }
+    description.push_str(&format!("\n  {line}: {}", line_text.trim_end()));
+
+    let hint = if message.starts_with("unknown field") {
+        Some("check the spelling of the key and that it is in the right section, see the README")
+    } else if message.starts_with("missing field") {
+        Some("this key is required in the section")
+    } else if message.contains("expected a string") || message.contains("expected `") {
+        Some("string values must be quoted")
+    } else if message.contains("duplicate key") {
+        Some("every key may only appear once per section")
+    } else {
+        None
+    };
+    if let Some(hint) = hint {
+        description.push_str(&format!("\n  hint: {hint}"));
+    }
+    description
+}
+
+async fn read_source(source: &str) -> Result<String> {
+    if source == "-" {
+        let mut config_str = String::new();
+        tokio::io::stdin()
+            .read_to_string(&mut config_str)
+            .await
+            .context("Failed to read configuration from stdin")?;
+        return Ok(config_str);
+    }
+
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let response = reqwest::Client::new()
+            .get(source)
+            .timeout(FETCH_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to fetch configuration from {source}"))?;
+        return response
+            .text()
+            .await
+            .with_context(|| format!("Failed to read configuration body from {source}"));
+    }
+
+    std::fs::read_to_string(source).with_context(|| format!("Failed to read {source}"))
+}
+
+fn split_domains(domains: &[String], includes: &mut Vec<String>, excludes: &mut Vec<String>) {
+    for domain in domains {
+        if let Some(stripped_domain) = domain.strip_prefix('!') {
+            excludes.push(stripped_domain.to_string());
+        } else {
+            includes.push(domain.clone());
+        }
+    }
 }
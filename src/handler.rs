@@ -1,277 +1,2143 @@
 use anyhow::Result;
+use arc_swap::ArcSwap;
 use futures::StreamExt;
 use futures_util::stream::FuturesUnordered;
 use hickory_client::proto::rr::Name;
-use hickory_proto::{op::Message, rustls::client_config};
+use hickory_proto::{
+    op::{Edns, Message, Query},
+    rr::{
+        DNSClass, LowerName, RData, Record, RecordType,
+        rdata::{
+            A, AAAA, TXT,
+            opt::{EdnsCode, EdnsOption},
+        },
+    },
+    rustls::default_provider,
+    serialize::binary::{BinDecodable, BinEncodable, BinEncoder},
+    xfer::Protocol,
+};
 use hickory_server::{
-    authority::MessageResponseBuilder,
+    authority::{MessageRequest, MessageResponse, MessageResponseBuilder},
     proto::op::{Header, MessageType, OpCode, ResponseCode},
     server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
 };
-use rustls::ClientConfig;
+use rand::seq::SliceRandom;
+use rustls::{ClientConfig, RootCertStore};
 use std::{
-    sync::Arc,
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, OnceLock},
     time::{Duration, Instant},
 };
 
 use crate::{
-    client::{DnsClientEntry, RetryableClient},
-    config::Config,
+    cache::{self, CacheKey, DnsCache, Refreshes},
+    capture,
+    client::{ClientOptions, DnsClientEntry, RetryableClient, Transport},
+    config::{
+        BlockAction, Blocklist, CacheConfig, ChaosConfig, Config, DebugProvider, DomainRules,
+        GroupInfo, GroupOptions, LogConfig, MaintenanceConfig, NonRecursive, RaceConfig, RaceMode,
+        RebindProtection, ResponseConfig, RootMode, RuleConflict, SpecialUseConfig, StartupConfig,
+        TlsRoots, WinnerPolicy,
+    },
+    logger,
+    observer::{self, ProviderTiming, QueryObserver, RaceEvent},
+    retransmit::RetransmitFilter,
+    servfail::ServfailMemory,
+    stats::STATS,
+    zone::{LocalRecords, LocalZone, RootHints},
 };
 
-const ALPN_H2: &[u8] = b"h2";
+const ALPN_H2: &[u8] = b"h2";
+/// EDNS option code (from the local/experimental range, RFC 6891) naming the answer's source
+const DEBUG_OPTION_CODE: u16 = 65001;
+/// EDNS padding option code (RFC 7830)
+const PADDING_OPTION_CODE: u16 = 12;
+/// TTL of the loopback addresses answered for `localhost`
+const LOCALHOST_TTL: u32 = 300;
+
+pub struct RaceHandler {
+    dns_clients: Vec<DnsClientEntry>,
+    domain_groups: Vec<GroupInfo>,
+    rebind_protection: RebindProtection,
+    /// Domains that are never answered from the cache, cached or blocked
+    bypass: Vec<String>,
+    race: RaceConfig,
+    cache: Option<Arc<dyn DnsCache>>,
+    cache_config: CacheConfig,
+    /// Providers taken over unchanged from the previous handler, whose entries
+    /// stay in a cache shared with it
+    kept_providers: Vec<String>,
+    /// Set once a reload has replaced this handler; see [`SharedHandler::replace`]
+    retired: tokio::sync::RwLock<bool>,
+    /// Cache keys being refreshed in the background, kept across reloads
+    refreshes: Arc<Refreshes>,
+    servfail: Option<ServfailMemory>,
+    retransmits: Option<RetransmitFilter>,
+    startup: StartupConfig,
+    /// Sorted from the most to the least specific zone
+    local_zones: Vec<LocalZone>,
+    local_records: LocalRecords,
+    /// Sorted by blocklist name
+    blocklists: Vec<(String, Blocklist)>,
+    response: ResponseConfig,
+    chaos: ChaosConfig,
+    special_use: SpecialUseConfig,
+    root_mode: RootMode,
+    /// Loaded in `hints` mode only
+    root_hints: Option<RootHints>,
+    maintenance: MaintenanceConfig,
+    rule_conflict: RuleConflict,
+    log: LogConfig,
+    observer: Option<Arc<dyn QueryObserver>>,
+    tls_roots: TlsRoots,
+    client_config: Arc<ClientConfig>,
+}
+
+impl RaceHandler {
+    /// Builds a handler from the configuration. On reload, `previous` is the running
+    /// handler: the connections of providers whose upstream settings are unchanged
+    /// are kept, and so are their cache entries if the cache settings are unchanged.
+    /// With `connect_wait_ms`, it returns once the new providers have connected.
+    pub async fn new(config: &Config, previous: Option<&RaceHandler>) -> Result<Self> {
+        let mut dns_clients = Vec::new();
+        // Providers that answer as before, so their cache entries stay valid
+        let mut kept_providers = Vec::new();
+        // Keeping the TLS configuration lets unchanged providers keep their connections
+        let client_config = match previous {
+            Some(previous) if previous.tls_roots == config.tls.roots => {
+                previous.client_config.clone()
+            }
+            _ => Arc::new(create_client_config(config.tls.roots)?),
+        };
+
+        let providers = config.get_providers()?;
+        for provider in providers {
+            let options = ClientOptions {
+                max_response_size: config.limits.max_response_size,
+                jitter: config.retry.jitter,
+                max_retries: config.retry.max_retries,
+                query_timeout: provider.timeout,
+                json_path: provider.json_path.clone(),
+                reuse: provider.reuse,
+                dscp: provider.dscp,
+                netns: provider.netns.clone(),
+                log_tls_details: config.log.tls_details,
+                max_connection_lifetime: provider.max_connection_lifetime,
+                paths: provider.paths.clone(),
+                recursion_desired: provider.recursion_desired,
+            };
+            let reusable = previous.and_then(|previous| {
+                previous.dns_clients.iter().find(|entry| {
+                    entry.name == provider.name
+                        && entry.client.matches(
+                            provider.addr,
+                            &provider.hostname,
+                            &client_config,
+                            &options,
+                        )
+                })
+            });
+            let client = match reusable {
+                Some(entry) => {
+                    tracing::debug!("Keeping the connection to <{}>", provider.hostname);
+                    if entry.drop_types == provider.drop_types
+                        && entry.authoritative_negative == provider.authoritative_negative
+                    {
+                        kept_providers.push(provider.name.clone());
+                    }
+                    entry.client.clone()
+                }
+                None => {
+                    RetryableClient::new(
+                        provider.addr,
+                        &provider.hostname,
+                        client_config.clone(),
+                        options,
+                    )
+                    .await?
+                }
+            };
+            let latency = STATS.provider_latency(&provider.name);
+            dns_clients.push(DnsClientEntry {
+                client,
+                name: provider.name,
+                domain_rules: provider.domain_rules,
+                drop_types: provider.drop_types,
+                authoritative_negative: provider.authoritative_negative,
+                tier: provider.tier,
+                priority: provider.priority,
+                query_classes: provider.query_classes,
+                monitor: provider.monitor,
+                log: provider.log,
+                latency,
+                transport: if provider.json_path.is_some() {
+                    Transport::DohJson
+                } else {
+                    Transport::Doh
+                },
+            });
+        }
+
+        warn_overlapping_rules(&dns_clients, config.routing.conflict);
+
+        let mut local_zones = Vec::new();
+        for (origin, path) in &config.local_zones {
+            local_zones.push(LocalZone::load(origin, path).await?);
+        }
+        local_zones.sort_by_key(|zone| std::cmp::Reverse(zone.origin().num_labels()));
+        let local_records = LocalRecords::new(config.get_local_records()?);
+        let root_hints = match (&config.root.mode, &config.root.hints) {
+            (RootMode::Hints, Some(path)) => Some(RootHints::load(path).await?),
+            _ => None,
+        };
+
+        let mut blocklists: Vec<_> = config
+            .blocklists
+            .iter()
+            .map(|(name, blocklist)| (name.clone(), blocklist.clone()))
+            .collect();
+        blocklists.sort_by(|a, b| a.0.cmp(&b.0));
+        if let Some((name, _)) = blocklists.iter().find(|(_, blocklist)| {
+            blocklist.action == BlockAction::CustomIp && blocklist.custom_ip.is_empty()
+        }) {
+            anyhow::bail!("Blocklist {name} uses the custom_ip action but sets no custom_ip");
+        }
+        if config.maintenance.enabled {
+            if config.maintenance.action == BlockAction::CustomIp
+                && config.maintenance.custom_ip.is_empty()
+            {
+                anyhow::bail!("Maintenance mode uses the custom_ip action but sets no custom_ip");
+            }
+            tracing::warn!(
+                "Maintenance mode is enabled, every query is answered with {:?}",
+                config.maintenance.action
+            );
+        }
+
+        // The running handler keeps answering meanwhile
+        if previous.is_some() && config.reload.connect_wait_ms > 0 {
+            let timeout = Duration::from_millis(config.reload.connect_wait_ms);
+            let connected =
+                futures::future::join_all(dns_clients.iter().map(|e| e.client.wait_connected()));
+            if tokio::time::timeout(timeout, connected).await.is_err() {
+                tracing::warn!(
+                    "Not every provider connected within {:?}, reloading anyway",
+                    timeout
+                );
+            }
+        }
+
+        let cache = if config.cache.negative || config.cache.positive {
+            match reusable_cache(previous, &config.cache) {
+                Some(cache) => Some(cache),
+                None => Some(cache::create(&config.cache)),
+            }
+        } else {
+            None
+        };
+
+        STATS.retain_providers(|name| dns_clients.iter().any(|entry| entry.name == name));
+
+        Ok(Self {
+            dns_clients,
+            domain_groups: config.get_groups(),
+            rebind_protection: config.rebind_protection.clone(),
+            bypass: config.bypass.domains.clone(),
+            race: config.race.clone(),
+            cache,
+            cache_config: config.cache.clone(),
+            kept_providers,
+            retired: Default::default(),
+            refreshes: previous
+                .map_or_else(Default::default, |previous| previous.refreshes.clone()),
+            servfail: (config.race.servfail_threshold > 0).then(|| {
+                ServfailMemory::new(
+                    config.race.servfail_threshold,
+                    Duration::from_millis(config.race.servfail_memory_ms),
+                )
+            }),
+            retransmits: (config.limits.retransmit_window_ms > 0).then(|| {
+                RetransmitFilter::new(Duration::from_millis(config.limits.retransmit_window_ms))
+            }),
+            startup: config.startup.clone(),
+            local_zones,
+            local_records,
+            blocklists,
+            response: config.response.clone(),
+            chaos: config.chaos.clone(),
+            special_use: config.special_use.clone(),
+            root_mode: config.root.mode,
+            root_hints,
+            maintenance: config.maintenance.clone(),
+            rule_conflict: config.routing.conflict,
+            log: config.log.clone(),
+            observer: observer::create(config.race.observer).map(Arc::from),
+            tls_roots: config.tls.roots,
+            client_config,
+        })
+    }
+
+    /// Races the query of a cached entry that is about to expire again in the
+    /// background, so that the entry is replaced before it expires. The answer
+    /// is cached as usual and not sent anywhere.
+    fn prefetch(self: &Arc<Self>, request: &Request, key: CacheKey) {
+        let Some(refresh) = self.refreshes.begin(key) else {
+            return;
+        };
+        let query = match request.to_bytes() {
+            Ok(query) => query,
+            Err(e) => {
+                tracing::error!("Failed to encode query for refresh: {}", e);
+                return;
+            }
+        };
+        let (src, protocol) = (request.src(), request.protocol());
+        let handler = self.clone();
+        tokio::spawn(async move {
+            let _refresh = refresh;
+            let message = match MessageRequest::from_bytes(&query) {
+                Ok(message) => message,
+                Err(e) => {
+                    tracing::error!("Failed to decode query for refresh: {}", e);
+                    return;
+                }
+            };
+            let request = Request::new(message, src, protocol);
+            if let Ok(request_info) = request.request_info() {
+                tracing::debug!("Refreshing {} in the background", request_info.query.name());
+            }
+            handler
+                .resolve(&request, DiscardResponse, &mut None, true)
+                .await;
+        });
+    }
+
+    /// Waits until any provider has connected, returning false on timeout
+    pub async fn wait_for_connection(&self, timeout: Duration) -> bool {
+        let mut connected = self
+            .dns_clients
+            .iter()
+            .map(|entry| entry.client.wait_connected())
+            .collect::<FuturesUnordered<_>>();
+        tokio::time::timeout(timeout, connected.next())
+            .await
+            .is_ok_and(|connected| connected.is_some())
+    }
+
+    /// Finds the options of the first domain group whose rules match the query,
+    /// falling back to the first catch-all group (one without include patterns)
+    fn group_options(&self, query_name: &str) -> Option<&GroupOptions> {
+        self.domain_groups
+            .iter()
+            .find(|(_, domain_rules, _)| {
+                !domain_rules.0.is_empty()
+                    && Self::matches_domain(query_name, domain_rules, self.rule_conflict)
+            })
+            .or_else(|| {
+                self.domain_groups
+                    .iter()
+                    .find(|(_, domain_rules, _)| domain_rules.0.is_empty())
+            })
+            .map(|(_, _, options)| options)
+    }
+
+    /// Caches a winning answer if positive caching is enabled
+    async fn cache_positive(&self, key: &CacheKey, decision: &RaceDecision) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        if !self.cache_config.positive {
+            return;
+        }
+        let RaceDecision::Winner {
+            provider, message, ..
+        } = decision
+        else {
+            return;
+        };
+
+        if let Some(ttl) = cache::positive_ttl(message) {
+            tracing::debug!(
+                "Caching answer for {} from {} ({:?})",
+                key.name,
+                provider,
+                ttl
+            );
+            self.insert_cached(cache, key.clone(), message, ttl, provider)
+                .await;
+        }
+    }
+
+    /// Caches an NXDOMAIN fallback if it came from a provider trusted for negative answers
+    async fn cache_negative(&self, key: CacheKey, decision: &RaceDecision) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        if !self.cache_config.negative {
+            return;
+        }
+        let RaceDecision::Fallback {
+            provider, message, ..
+        } = decision
+        else {
+            return;
+        };
+
+        let trusted = self
+            .dns_clients
+            .iter()
+            .any(|entry| entry.name == *provider && entry.authoritative_negative);
+        if !trusted {
+            return;
+        }
+        if let Some(ttl) = cache::negative_ttl(message) {
+            let ttl = ttl.min(Duration::from_secs(self.cache_config.max_negative_ttl));
+            tracing::debug!(
+                "Caching NXDomain for {} from {} ({:?})",
+                key.name,
+                provider,
+                ttl
+            );
+            self.insert_cached(cache, key, message, ttl, provider).await;
+        }
+    }
+
+    /// Inserts into the cache unless a reload has retired this handler. The read
+    /// lock is held across the insert, so retiring waits for inserts under way.
+    async fn insert_cached(
+        &self,
+        cache: &Arc<dyn DnsCache>,
+        key: CacheKey,
+        message: &Message,
+        ttl: Duration,
+        provider: &str,
+    ) {
+        let retired = self.retired.read().await;
+        if *retired {
+            return;
+        }
+        cache.insert(key, message.clone(), ttl, provider).await;
+    }
+
+    /// Whether the query name must skip the cache and blocklists
+    fn is_bypassed(&self, query_name: &str) -> bool {
+        let query_name = query_name.trim_end_matches('.');
+        self.bypass
+            .iter()
+            .any(|domain| is_domain_match(query_name, domain.trim_end_matches('.')))
+    }
+
+    /// Finds the first blocklist containing the query name
+    fn blocklist(&self, query_name: &str) -> Option<&(String, Blocklist)> {
+        let query_name = query_name.trim_end_matches('.');
+        self.blocklists.iter().find(|(_, blocklist)| {
+            blocklist
+                .domains
+                .iter()
+                .any(|domain| is_domain_match(query_name, domain.trim_end_matches('.')))
+        })
+    }
+
+    /// Whether a response must be rejected as a potential DNS rebinding attack
+    fn is_rebind_attempt(&self, query_name: &str, message: &Message) -> bool {
+        if !self.rebind_protection.enabled {
+            return false;
+        }
+
+        let query_name = query_name.trim_end_matches('.');
+        if self
+            .rebind_protection
+            .allow_zones
+            .iter()
+            .any(|zone| is_domain_match(query_name, zone.trim_end_matches('.')))
+        {
+            return false;
+        }
+
+        message.answers().iter().any(|record| match record.data() {
+            RData::A(a) => is_private_ip(IpAddr::V4(a.0)),
+            RData::AAAA(aaaa) => is_private_ip(IpAddr::V6(aaaa.0)),
+            _ => false,
+        })
+    }
+
+    fn matches_domain(
+        query_name: &str,
+        domain_rules: &(Vec<String>, Vec<String>),
+        conflict: RuleConflict,
+    ) -> bool {
+        matches!(
+            domain_rule_match(query_name, domain_rules, conflict),
+            RuleMatch::All | RuleMatch::Included(_)
+        )
+    }
+}
+
+/// The rule that decides whether a name matches a set of domain rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleMatch<'a> {
+    /// The include list is empty, so every name matches
+    All,
+    /// Included by this pattern
+    Included(&'a str),
+    /// Excluded by this pattern, which takes precedence over the include list
+    /// unless a longer include pattern matches under [`RuleConflict::LongestMatch`]
+    Excluded(&'a str),
+    /// No include pattern matches
+    None,
+}
+
+/// Matches a name against domain rules; see [`RuleMatch`]
+pub fn domain_rule_match<'a>(
+    query_name: &str,
+    domain_rules: &'a DomainRules,
+    conflict: RuleConflict,
+) -> RuleMatch<'a> {
+    let (includes, excludes) = domain_rules;
+
+    // If the include list is empty, it means process all domains
+    if includes.is_empty() {
+        return RuleMatch::All;
+    }
+
+    let query_name = query_name.trim_end_matches('.');
+
+    // The longest matching pattern of each list is the most specific one
+    let exclude = excludes
+        .iter()
+        .filter(|exclude| query_name.ends_with(exclude.as_str()))
+        .max_by_key(|exclude| exclude.len());
+    let include = includes
+        .iter()
+        .filter(|domain| is_domain_match(query_name, domain))
+        .max_by_key(|domain| domain.len());
+
+    match (exclude, include) {
+        (Some(exclude), Some(include))
+            if conflict == RuleConflict::LongestMatch && include.len() > exclude.len() =>
+        {
+            RuleMatch::Included(include)
+        }
+        (Some(exclude), _) => RuleMatch::Excluded(exclude),
+        (None, Some(include)) => RuleMatch::Included(include),
+        (None, None) => RuleMatch::None,
+    }
+}
+
+/// Selects the providers a name is raced across: those whose include rules
+/// match it, or the catch-all providers (without include rules) if none do.
+/// Returns whether specific providers matched.
+pub fn select_by_domain<'a, T>(
+    candidates: Vec<&'a T>,
+    query_name: &str,
+    conflict: RuleConflict,
+    domain_rules: impl Fn(&T) -> &DomainRules,
+) -> (Vec<&'a T>, bool) {
+    let matching: Vec<_> = candidates
+        .iter()
+        .copied()
+        .filter(|candidate| {
+            let domain_rules = domain_rules(candidate);
+            !domain_rules.0.is_empty()
+                && RaceHandler::matches_domain(query_name, domain_rules, conflict)
+        })
+        .collect();
+    if matching.is_empty() {
+        let catch_all = candidates
+            .into_iter()
+            .filter(|candidate| domain_rules(candidate).0.is_empty())
+            .collect();
+        (catch_all, false)
+    } else {
+        (matching, true)
+    }
+}
+
+/// A [`RaceHandler`] that can be replaced at runtime, e.g. after a config reload.
+/// Queries load the current handler without locking; a query that is already
+/// running keeps the handler it started with.
+#[derive(Clone)]
+pub struct SharedHandler {
+    inner: Arc<ArcSwap<RaceHandler>>,
+}
+
+impl SharedHandler {
+    pub fn new(handler: RaceHandler) -> Self {
+        Self {
+            inner: Arc::new(ArcSwap::from_pointee(handler)),
+        }
+    }
+
+    /// The handler currently answering queries
+    pub fn current(&self) -> Arc<RaceHandler> {
+        self.inner.load_full()
+    }
+
+    /// Swaps in `handler` and retires the previous one, whose queries still in
+    /// flight stop caching their answers. Only then is a cache taken over from it
+    /// purged of the providers `handler` did not keep, so no answer of theirs
+    /// can be inserted after the purge.
+    pub async fn replace(&self, handler: RaceHandler) {
+        let handler = Arc::new(handler);
+        let previous = self.inner.swap(handler.clone());
+        *previous.retired.write().await = true;
+        if let (Some(cache), Some(previous_cache)) = (&handler.cache, &previous.cache)
+            && Arc::ptr_eq(cache, previous_cache)
+        {
+            let entries = cache
+                .retain_providers(&|provider| handler.kept_providers.iter().any(|p| p == provider))
+                .await;
+            tracing::info!("Keeping {} cache entries", entries);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for SharedHandler {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        let handler = self.current();
+        handler.handle_request(request, response_handle).await
+    }
+}
+
+impl RaceHandler {
+    /// Answers one client request and writes its access log line. Local answers
+    /// (local records and zones, special-use names, root queries as configured in
+    /// `[root]`, see [`Self::root_response`]) come first, then blocklists and the
+    /// cache; everything else is raced across the providers.
+    async fn handle_request<R: ResponseHandler>(
+        self: &Arc<Self>,
+        request: &Request,
+        response_handle: R,
+    ) -> ResponseInfo {
+        let _in_flight = match (&self.retransmits, request.protocol()) {
+            (Some(retransmits), Protocol::Udp) => {
+                let name = match request.request_info() {
+                    Ok(request_info) => request_info.query.name().clone(),
+                    Err(_) => LowerName::default(),
+                };
+                match retransmits.begin(request.src(), request.id(), name) {
+                    Some(in_flight) => Some(in_flight),
+                    None => {
+                        tracing::debug!(
+                            "Dropping retransmitted query {} from {}",
+                            request.id(),
+                            request.src()
+                        );
+                        return create_servfail_response(request.id());
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        STATS.record_query();
+        let start = Instant::now();
+        let response_handle = SentAt::new(response_handle);
+        let sent = response_handle.sent.clone();
+        let mut source = None;
+        let info = self
+            .resolve(request, response_handle, &mut source, false)
+            .await;
+        // resolve() keeps going after the answer is out (draining losers, waiting
+        // for the race summary), so the latency stops at the send
+        let end = sent.get().copied().unwrap_or_else(Instant::now);
+        log_access(
+            request,
+            &info,
+            source.as_deref(),
+            end.duration_since(start),
+            self.log.access_log_anonymize,
+        );
+        info
+    }
+
+    /// Answers queries for the root name (`.`) in class IN as `[root]` configures:
+    /// `None` leaves them to the race, which is the default. Monitoring tools probe
+    /// the root with NS and SOA queries, and some providers answer those oddly
+    /// (REFUSED, or a referral without glue), so they can be answered from a root
+    /// hints file or refused instead.
+    fn root_response(&self, query: &Query, recursion_desired: bool) -> Option<Message> {
+        if !query.name().is_root() || query.query_class() != DNSClass::IN {
+            return None;
+        }
+        match self.root_mode {
+            RootMode::Forward => None,
+            RootMode::Hints => self.root_hints.as_ref()?.lookup(query, recursion_desired),
+            RootMode::Refuse => {
+                let mut message = Message::error_msg(0, OpCode::Query, ResponseCode::Refused);
+                message
+                    .set_recursion_desired(recursion_desired)
+                    .add_query(query.clone());
+                Some(message)
+            }
+        }
+    }
+
+    /// Sends an error response without records and returns its [`ResponseInfo`],
+    /// which is also returned if sending failed
+    async fn send_error<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: &mut R,
+        response_code: ResponseCode,
+    ) -> ResponseInfo {
+        let message = Message::error_msg(request.id(), request.op_code(), response_code);
+        if let Err(e) = send(&message, request, response_handle, &self.response, None).await {
+            tracing::error!("Failed to send {:?} DNS response: {}", response_code, e);
+        }
+        let mut header = Header::new();
+        header.set_id(request.id());
+        header.set_message_type(MessageType::Response);
+        header.set_op_code(request.op_code());
+        header.set_response_code(response_code);
+        ResponseInfo::from(header)
+    }
+
+    /// Answers a request, setting `source` to where the answer came from.
+    /// A background refresh (`refreshing`) does not read the cache, and as it
+    /// has no client, sends nothing and is left out of the stats and the observer.
+    async fn resolve<R: ResponseHandler>(
+        self: &Arc<Self>,
+        request: &Request,
+        mut response_handle: R,
+        source: &mut Option<String>,
+        refreshing: bool,
+    ) -> ResponseInfo {
+        let request_id = request.id();
+        let request_info = match request.request_info() {
+            Ok(request_info) => request_info,
+            Err(e) => {
+                tracing::warn!("Malformed request from {}: {}", request.src(), e);
+                return self
+                    .send_error(request, &mut response_handle, ResponseCode::FormErr)
+                    .await;
+            }
+        };
+        let query = request_info.query;
+        let query_name = query.name().to_string();
+
+        // A forwarding proxy has no zones to transfer, and racing a transfer would
+        // only relay a provider's refusal or stream a zone through the race
+        if matches!(query.query_type(), RecordType::AXFR | RecordType::IXFR) {
+            tracing::warn!(
+                "Refusing zone transfer ({}) of {} from {}",
+                query.query_type(),
+                query_name,
+                request.src()
+            );
+            return self
+                .send_error(request, &mut response_handle, ResponseCode::Refused)
+                .await;
+        }
+        let group_options = self.group_options(&query_name).cloned().unwrap_or_default();
+        // Sampled out queries skip their INFO lines; warnings and errors are always logged
+        let log_query = self.log.sample_rate >= 1.0 || rand::random_bool(self.log.sample_rate);
+
+        if self.maintenance.enabled {
+            let message = block_response(
+                self.maintenance.action,
+                &self.maintenance.custom_ip,
+                self.maintenance.ttl,
+                query.original(),
+                request_id,
+                request.recursion_desired(),
+            );
+            if log_query {
+                tracing::info!(
+                    "⏸ maintenance: {}| {}",
+                    format_response_code(message.response_code()),
+                    format_answers(message.query(), message.answers())
+                );
+            }
+            *source = Some("maintenance".to_string());
+            return match send(
+                &message,
+                request,
+                &mut response_handle,
+                &self.response,
+                Some("maintenance"),
+            )
+            .await
+            {
+                Ok(info) => info,
+                Err(e) => {
+                    tracing::error!("Failed to send maintenance DNS response: {}", e);
+                    create_servfail_response(request_id)
+                }
+            };
+        }
+        let cache_key = CacheKey {
+            name: query.name().clone(),
+            query_type: query.query_type(),
+            query_class: query.query_class(),
+        };
+        let bypass = self.is_bypassed(&query_name);
+        if bypass {
+            tracing::debug!("Bypassing cache and blocklists for {}", query_name);
+        }
+
+        if let Some(mut message) =
+            chaos_response(&self.chaos, query.original(), request.recursion_desired())
+                .or_else(|| {
+                    self.local_records
+                        .lookup(query.original(), request.recursion_desired())
+                })
+                .or_else(|| {
+                    self.local_zones
+                        .iter()
+                        .find_map(|zone| zone.lookup(query.original(), request.recursion_desired()))
+                })
+                .or_else(|| {
+                    special_use_response(
+                        &self.special_use,
+                        query.original(),
+                        request.recursion_desired(),
+                    )
+                })
+                .or_else(|| self.root_response(query.original(), request.recursion_desired()))
+        {
+            message.set_id(request_id);
+            if log_query {
+                tracing::info!(
+                    "⌂ local: {}| {}",
+                    format_response_code(message.response_code()),
+                    format_answers(message.query(), message.answers())
+                );
+            }
+            *source = Some("local".to_string());
+            return match send(
+                &message,
+                request,
+                &mut response_handle,
+                &self.response,
+                Some("local"),
+            )
+            .await
+            {
+                Ok(info) => info,
+                Err(e) => {
+                    tracing::error!("Failed to send local zone DNS response: {}", e);
+                    create_servfail_response(request_id)
+                }
+            };
+        }
+
+        if !bypass && let Some((name, blocklist)) = self.blocklist(&query_name) {
+            let message = block_response(
+                blocklist.action,
+                &blocklist.custom_ip,
+                blocklist.ttl,
+                query.original(),
+                request_id,
+                request.recursion_desired(),
+            );
+            if log_query {
+                tracing::info!(
+                    "⊘ {}: {}| {}",
+                    name,
+                    format_response_code(message.response_code()),
+                    format_answers(message.query(), message.answers())
+                );
+            }
+            *source = Some(format!("blocklist {name}"));
+            return match send(
+                &message,
+                request,
+                &mut response_handle,
+                &self.response,
+                Some(&format!("blocklist {name}")),
+            )
+            .await
+            {
+                Ok(info) => info,
+                Err(e) => {
+                    tracing::error!("Failed to send blocked DNS response: {}", e);
+                    create_servfail_response(request_id)
+                }
+            };
+        }
+
+        let non_recursive = if request.recursion_desired() {
+            NonRecursive::Forward
+        } else {
+            self.response.non_recursive
+        };
+        let cached = if !bypass
+            && !refreshing
+            && non_recursive != NonRecursive::Refuse
+            && let Some(cache) = &self.cache
+        {
+            let cached = cache.get(&cache_key).await;
+            STATS.record_cache_lookup(cached.is_some());
+            cached
+        } else {
+            None
+        };
+        if let Some((mut message, remaining)) = cached {
+            message.set_id(request_id);
+            if log_query {
+                tracing::info!(
+                    "⚡ cache: {}| {}",
+                    format_response_code(message.response_code()),
+                    format_answers(message.query(), message.answers())
+                );
+            }
+            // Non-recursive queries must not reach the providers, not even to refresh
+            if non_recursive == NonRecursive::Forward
+                && self
+                    .cache_config
+                    .prefetch_window_secs
+                    .is_some_and(|window| remaining <= Duration::from_secs(window))
+            {
+                self.prefetch(request, cache_key.clone());
+            }
+            *source = Some("cache".to_string());
+            return match send(
+                &message,
+                request,
+                &mut response_handle,
+                &self.response,
+                Some("cache"),
+            )
+            .await
+            {
+                Ok(info) => info,
+                Err(e) => {
+                    tracing::error!("Failed to send cached DNS response: {}", e);
+                    create_servfail_response(request_id)
+                }
+            };
+        }
+
+        if non_recursive != NonRecursive::Forward {
+            if log_query {
+                tracing::info!("Refusing non-recursive query for {}", query_name);
+            }
+            return self
+                .send_error(request, &mut response_handle, ResponseCode::Refused)
+                .await;
+        }
+
+        let class_clients: Vec<_> = self
+            .dns_clients
+            .iter()
+            .filter(|dns_client_entry| {
+                dns_client_entry.query_classes.is_empty()
+                    || dns_client_entry
+                        .query_classes
+                        .contains(&query.query_class())
+            })
+            .collect();
+
+        let (clients_to_use, specific) =
+            select_by_domain(class_clients, &query_name, self.rule_conflict, |entry| {
+                &entry.domain_rules
+            });
+        tracing::debug!(
+            "Selected providers for domain {}: {}",
+            query_name,
+            clients_to_use
+                .iter()
+                .map(|entry| entry.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if specific && log_query {
+            tracing::info!("Using specific DNS provider for domain: {}", query_name);
+        }
+
+        if clients_to_use
+            .iter()
+            .all(|dns_client_entry| dns_client_entry.monitor)
+        {
+            // Monitor providers never answer, so the client gets SERVFAIL rather than a timeout
+            tracing::error!("No DNS provider available for domain: {}", query_name);
+            return self
+                .send_error(request, &mut response_handle, ResponseCode::ServFail)
+                .await;
+        }
+
+        if self.startup.fail_fast
+            && !clients_to_use.iter().any(|dns_client_entry| {
+                !dns_client_entry.monitor && dns_client_entry.client.has_connected()
+            })
+        {
+            tracing::warn!(
+                "No provider connected yet, answering ServFail for domain: {}",
+                query_name
+            );
+            return self
+                .send_error(request, &mut response_handle, ResponseCode::ServFail)
+                .await;
+        }
+
+        let group_timeout = group_options.timeout_ms.map(Duration::from_millis);
+        let mut responses: Vec<ProviderResponse> = Vec::new();
+        let mut sent_response_code = None;
+        let mut rebind_rejected = false;
+        let mut summary = Vec::new();
+        let mut summary_deadline = None;
+        let mut timings = Vec::new();
+        let observer = if refreshing {
+            None
+        } else {
+            self.observer.as_ref()
+        };
+        let mut pending = 0;
+
+        // Race the lowest tier first and only escalate when it produced no winner.
+        // Providers that keep answering SERVFAIL for this query are raced after all others.
+        // Each entry is (not sticky, deprioritized, tier, failover rank), so sorting puts a
+        // sticky provider first. In failover mode, the rank gives each provider its own stage.
+        let mut race_order: Vec<(bool, bool, u8, usize)> = clients_to_use
+            .iter()
+            .map(|entry| {
+                let deprioritized = self
+                    .servfail
+                    .as_ref()
+                    .is_some_and(|servfail| servfail.is_deprioritized(&entry.name, &cache_key));
+                (true, deprioritized, entry.tier, 0)
+            })
+            .collect();
+        if self.race.sticky
+            && let Some(index) = sticky_provider(&query_name, &clients_to_use, &race_order)
+        {
+            race_order[index].0 = false;
+        }
+        if self.race.mode == RaceMode::Failover {
+            // Rank by priority, then name within each stage; monitor providers keep
+            // rank 0 and are queried alongside the first provider of their stage
+            let mut ranked: Vec<usize> = (0..clients_to_use.len())
+                .filter(|&index| !clients_to_use[index].monitor)
+                .collect();
+            ranked.sort_by(|&a, &b| {
+                race_order[a]
+                    .cmp(&race_order[b])
+                    .then_with(|| clients_to_use[a].priority.cmp(&clients_to_use[b].priority))
+                    .then_with(|| clients_to_use[a].name.cmp(&clients_to_use[b].name))
+            });
+            let mut previous = None;
+            let mut rank = 0;
+            for index in ranked {
+                let stage = race_order[index];
+                rank = if previous == Some(stage) { rank + 1 } else { 0 };
+                previous = Some(stage);
+                race_order[index].3 = rank;
+            }
+        }
+        let mut stages = race_order.clone();
+        stages.sort_unstable();
+        stages.dedup();
+
+        let race_deadline = self
+            .race
+            .total_timeout_ms
+            .map(|ms| tokio::time::Instant::now() + Duration::from_millis(ms));
+        let mut timed_out = false;
+        for (i, stage) in stages.into_iter().enumerate() {
+            let (not_sticky, deprioritized, tier, _) = stage;
+            if i == 0 && !not_sticky && log_query {
+                tracing::info!("Querying sticky provider for {}", query_name);
+            } else if i == 1 && self.race.sticky {
+                tracing::warn!(
+                    "Sticky provider failed for {}, racing the others",
+                    query_name
+                );
+            } else if i > 0 && self.race.mode == RaceMode::Failover {
+                let next = clients_to_use
+                    .iter()
+                    .zip(&race_order)
+                    .find(|(entry, order)| **order == stage && !entry.monitor)
+                    .map_or("-", |(entry, _)| entry.name.as_str());
+                tracing::warn!("Failing over {} to {}", query_name, next);
+            } else if i > 0 && deprioritized {
+                tracing::warn!(
+                    "Escalating {} to providers deprioritized after SERVFAILs (tier {})",
+                    query_name,
+                    tier
+                );
+            } else if i > 0 {
+                tracing::warn!("Escalating {} to provider tier {}", query_name, tier);
+            }
+
+            let delays = if self.race.mode == RaceMode::Adaptive {
+                let delays = adaptive_delays(
+                    &clients_to_use,
+                    &race_order,
+                    stage,
+                    Duration::from_millis(self.race.adaptive_max_delay_ms),
+                );
+                if delays.iter().any(|delay| !delay.is_zero()) {
+                    tracing::debug!(
+                        "Adaptive start of {}: {}",
+                        query_name,
+                        clients_to_use
+                            .iter()
+                            .zip(&delays)
+                            .filter(|(_, delay)| !delay.is_zero())
+                            .map(|(entry, delay)| format!("{} +{:?}", entry.name, delay))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+                delays
+            } else {
+                vec![Duration::ZERO; clients_to_use.len()]
+            };
+            let mut futures = clients_to_use
+                .iter()
+                .zip(&race_order)
+                .zip(delays)
+                .filter(|((_, order), _)| **order == stage)
+                .map(move |((dns_client_entry, _), delay)| {
+                    let client = dns_client_entry.client.clone();
+                    let name_clone = Name::from(query.name());
+                    let query_type = query.query_type();
+                    let query_class = query.query_class();
+                    let name = dns_client_entry.name.clone();
+                    let transport = dns_client_entry.transport;
+                    let monitor = dns_client_entry.monitor;
+                    let log = dns_client_entry.log;
+                    let drop_types = &dns_client_entry.drop_types;
+                    let average = dns_client_entry.latency.clone();
+
+                    Box::pin(async move {
+                        // A delayed provider is never queried if the race ends first
+                        if !delay.is_zero() {
+                            tokio::time::sleep(delay).await;
+                        }
+                        let start = Instant::now();
+                        match client
+                            .query(name_clone, query_class, query_type, group_timeout)
+                            .await
+                        {
+                            Ok(response) => {
+                                let latency = Latency {
+                                    elapsed: start.elapsed(),
+                                    cold_rtt: response.reconnected.then_some(response.rtt),
+                                };
+                                average.record(latency.cold_rtt.unwrap_or(latency.elapsed));
+                                let mut message = response.response.into_message();
+                                if !drop_types.is_empty() {
+                                    drop_record_types(&mut message, drop_types);
+                                }
+                                Ok((message, latency, name, transport, monitor, log))
+                            }
+                            Err(e) => {
+                                // A failure counts as at least a timed-out attempt, so a
+                                // provider that fails fast is not ranked as a fast one
+                                average.record(
+                                    start.elapsed().max(client.attempt_timeout(group_timeout)),
+                                );
+                                Err((e, start.elapsed(), name, transport))
+                            }
+                        }
+                    })
+                })
+                .collect::<FuturesUnordered<_>>();
+
+            // Winner candidates held back until the race ends (see `WinnerPolicy::waits_for_all`)
+            let mut candidates: Vec<(Message, Latency, String, bool)> = Vec::new();
+            // With `prefer_authenticated`, how long unauthenticated candidates wait for an AD answer
+            let mut authenticated_deadline = None;
+            loop {
+                // Once the winner is sent, only wait for the summary up to its deadline;
+                // before that, held unauthenticated candidates only wait up to theirs,
+                // and nothing waits past the end of the race
+                let deadline = summary_deadline.or_else(|| {
+                    [authenticated_deadline, race_deadline]
+                        .into_iter()
+                        .flatten()
+                        .min()
+                });
+                let result = match deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline, futures.next()).await
+                    {
+                        Ok(result) => result,
+                        Err(_) => {
+                            timed_out = summary_deadline.is_none()
+                                && race_deadline.is_some_and(|race_deadline| {
+                                    tokio::time::Instant::now() >= race_deadline
+                                });
+                            break;
+                        }
+                    },
+                    None => futures.next().await,
+                };
+                let Some(result) = result else {
+                    break;
+                };
+
+                match result {
+                    Ok((mut message, elapsed, provider, transport, monitor, log)) => {
+                        let response_code = message.header().response_code();
+                        message.set_id(request_id);
+                        if let Some(servfail) = &self.servfail {
+                            if response_code == ResponseCode::ServFail {
+                                servfail.record_servfail(&provider, &cache_key);
+                            } else {
+                                servfail.record_answer(&provider, &cache_key);
+                            }
+                        }
+                        if self.race.summary {
+                            summary
+                                .push(format!("{provider}/{transport} {response_code} {elapsed}"));
+                        }
+                        if observer.is_some() {
+                            timings.push(ProviderTiming {
+                                provider: provider.clone(),
+                                transport,
+                                elapsed: elapsed.elapsed,
+                                response_code: Some(response_code),
+                            });
+                        }
+                        // Monitor providers are only observed and never win or serve as fallback
+                        if monitor {
+                            if log_query && log {
+                                tracing::info!(
+                                    "◌ {} (monitor): {}{} | {}",
+                                    provider,
+                                    format_response_code(response_code),
+                                    elapsed,
+                                    format_answers(message.query(), message.answers())
+                                );
+                            }
+                            continue;
+                        }
+                        if group_options.shuffle_answers {
+                            shuffle_answers(message.answers_mut(), query.query_type());
+                        }
+
+                        if is_good_response(response_code)
+                            && self.is_rebind_attempt(&query_name, &message)
+                        {
+                            tracing::warn!(
+                                "✘ {}: rejected private answer for public name | {}",
+                                provider,
+                                format_answers(message.query(), message.answers())
+                            );
+                            rebind_rejected = true;
+                            continue;
+                        }
+
+                        if sent_response_code.is_none()
+                            && is_winner_candidate(
+                                self.race.winner,
+                                response_code,
+                                &message,
+                                group_options.min_answers(query.query_type()),
+                            )
+                        {
+                            if self.race.winner.waits_for_all() {
+                                candidates.push((message, elapsed, provider, log));
+                                continue;
+                            }
+                            if self.race.prefer_authenticated && !message.authentic_data() {
+                                candidates.push((message, elapsed, provider, log));
+                                authenticated_deadline.get_or_insert_with(|| {
+                                    tokio::time::Instant::now()
+                                        + Duration::from_millis(self.race.authenticated_wait_ms)
+                                });
+                                continue;
+                            }
+                            let decision = RaceDecision::Winner {
+                                provider,
+                                message,
+                                elapsed,
+                            };
+                            match send_decision(
+                                &decision,
+                                request,
+                                &mut response_handle,
+                                &self.response,
+                                log_query && log,
+                                refreshing,
+                            )
+                            .await
+                            {
+                                Some(code) => {
+                                    sent_response_code = Some(code);
+                                    *source = decision.provider().map(str::to_string);
+                                    if !bypass {
+                                        self.cache_positive(&cache_key, &decision).await;
+                                    }
+                                    if self.race.summary {
+                                        summary_deadline = Some(
+                                            tokio::time::Instant::now()
+                                                + Duration::from_millis(self.race.summary_wait_ms),
+                                        );
+                                    }
+                                }
+                                None => {
+                                    // Keep the response around as a fallback candidate
+                                    if let RaceDecision::Winner {
+                                        provider, message, ..
+                                    } = decision
+                                    {
+                                        responses.push(ProviderResponse {
+                                            provider,
+                                            response_code,
+                                            message,
+                                        });
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+
+                        if log_query && log {
+                            tracing::info!(
+                                "◼︎ {}: {}{} | {}",
+                                provider,
+                                format_response_code(response_code),
+                                elapsed,
+                                format_answers(message.query(), message.answers())
+                            );
+                        }
+                        if sent_response_code.is_none() {
+                            responses.push(ProviderResponse {
+                                provider,
+                                response_code,
+                                message,
+                            });
+                        }
+                    }
+                    Err((e, elapsed, name, transport)) => {
+                        if self.race.summary {
+                            summary.push(format!("{name}/{transport} failed {elapsed:?}"));
+                        }
+                        if observer.is_some() {
+                            timings.push(ProviderTiming {
+                                provider: name.clone(),
+                                transport,
+                                elapsed,
+                                response_code: None,
+                            });
+                        }
+                        if !refreshing {
+                            STATS.record_error(&name);
+                        }
+                        tracing::error!(
+                            "Query failed: {:#}, {:?}, <{}/{}>",
+                            anyhow::Error::from(e),
+                            elapsed,
+                            name,
+                            transport
+                        );
+                    }
+                }
+            }
+
+            // Authenticated (AD) answers go first when preferred, then the fastest
+            candidates.sort_by_key(|(message, elapsed, ..)| {
+                (
+                    self.race.prefer_authenticated && !message.authentic_data(),
+                    elapsed.elapsed,
+                )
+            });
+            let mut candidates = candidates.into_iter();
+            let mut losers = Vec::new();
+            if sent_response_code.is_none()
+                && let Some((mut message, elapsed, mut provider, mut log)) = candidates.next()
+            {
+                if self.race.winner == WinnerPolicy::MergeAnswers {
+                    for (other, other_elapsed, other_provider, other_log) in candidates.by_ref() {
+                        // Never let unauthenticated records into an authenticated answer
+                        if self.race.prefer_authenticated
+                            && other.authentic_data() != message.authentic_data()
+                        {
+                            losers.push((other, other_elapsed, other_provider, other_log));
+                            continue;
+                        }
+                        // Answers that reach the records by another CNAME chain are not mixed in
+                        if !merge_answers(
+                            &mut message,
+                            &other,
+                            &Name::from(query.name()),
+                            query.query_type(),
+                        ) {
+                            losers.push((other, other_elapsed, other_provider, other_log));
+                            continue;
+                        }
+                        provider = format!("{provider}+{other_provider}");
+                        log |= other_log;
+                    }
+                }
+                let decision = RaceDecision::Winner {
+                    provider,
+                    message,
+                    elapsed,
+                };
+                sent_response_code = send_decision(
+                    &decision,
+                    request,
+                    &mut response_handle,
+                    &self.response,
+                    log_query && log,
+                    refreshing,
+                )
+                .await;
+                if sent_response_code.is_some() {
+                    *source = decision.provider().map(str::to_string);
+                    if !bypass {
+                        self.cache_positive(&cache_key, &decision).await;
+                    }
+                }
+                if sent_response_code.is_none()
+                    && let RaceDecision::Winner {
+                        provider, message, ..
+                    } = decision
+                {
+                    responses.push(ProviderResponse {
+                        provider,
+                        response_code: message.response_code(),
+                        message,
+                    });
+                }
+            }
+            for (message, elapsed, provider, log) in losers.into_iter().chain(candidates) {
+                if log_query && log {
+                    tracing::info!(
+                        "◼︎ {}: {}{} | {}",
+                        provider,
+                        format_response_code(message.response_code()),
+                        elapsed,
+                        format_answers(message.query(), message.answers())
+                    );
+                }
+            }
+
+            pending = futures.len();
+            if sent_response_code.is_some() {
+                break;
+            }
+            if timed_out {
+                tracing::warn!(
+                    "Race for {} timed out after {}ms",
+                    query_name,
+                    self.race.total_timeout_ms.unwrap_or_default()
+                );
+                break;
+            }
+        }
+
+        if sent_response_code.is_none() {
+            let decision = match select_fallback(responses) {
+                RaceDecision::AllFailed if rebind_rejected => RaceDecision::Rejected,
+                decision => decision,
+            };
+            sent_response_code = send_decision(
+                &decision,
+                request,
+                &mut response_handle,
+                &self.response,
+                log_query,
+                refreshing,
+            )
+            .await;
+            if sent_response_code.is_some() {
+                *source = decision.provider().map(str::to_string);
+                if !bypass {
+                    self.cache_negative(cache_key, &decision).await;
+                }
+            }
+        }
+
+        // Failed queries are always summarized, successful ones only when sampled
+        if self.race.summary && (log_query || sent_response_code != Some(ResponseCode::NoError)) {
+            if pending > 0 {
+                summary.push(format!("{pending} pending"));
+            }
+            tracing::info!(
+                "Σ {}({}) {} | {}",
+                query_name,
+                query.query_type(),
+                sent_response_code.map_or("-", |code| code.to_str()),
+                summary.join(", ")
+            );
+        }
+
+        if let Some(observer) = observer {
+            let event = RaceEvent {
+                name: query_name.clone(),
+                query_type: query.query_type(),
+                winner: source.clone(),
+                response_code: sent_response_code,
+                timings,
+            };
+            let observer = observer.clone();
+            tokio::spawn(async move { observer.on_race(event).await });
+        }
+
+        let mut header = Header::new();
+        header.set_id(request_id);
+        header.set_message_type(MessageType::Response);
+        header.set_op_code(OpCode::Query);
+        header.set_response_code(sent_response_code.unwrap_or(ResponseCode::ServFail));
+        ResponseInfo::from(header)
+    }
+}
+
+/// Response handle of background refreshes, which have no client to answer
+#[derive(Clone)]
+struct DiscardResponse;
+
+#[async_trait::async_trait]
+impl ResponseHandler for DiscardResponse {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        Ok(ResponseInfo::from(*response.header()))
+    }
+}
+
+/// Response handle that records when the response was handed to the transport
+#[derive(Clone)]
+struct SentAt<R> {
+    inner: R,
+    sent: Arc<OnceLock<Instant>>,
+}
+
+impl<R> SentAt<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            sent: Arc::new(OnceLock::new()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: ResponseHandler> ResponseHandler for SentAt<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let result = self.inner.send_response(response).await;
+        let _ = self.sent.set(Instant::now());
+        result
+    }
+}
+
+/// A response collected from one provider during the race
+struct ProviderResponse {
+    provider: String,
+    response_code: ResponseCode,
+    message: Message,
+}
+
+/// Time from sending a query to a provider until its response arrived
+#[derive(Debug, Clone, Copy)]
+struct Latency {
+    elapsed: Duration,
+    /// Round trip of the final attempt, set when the query had to reconnect
+    /// first so that cold-path latency is not mistaken for the provider's RTT
+    cold_rtt: Option<Duration>,
+}
+
+impl std::fmt::Display for Latency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.elapsed)?;
+        if let Some(rtt) = self.cold_rtt {
+            write!(f, " (cold, rtt {rtt:?})")?;
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of a race, decided before anything is sent to the client
+enum RaceDecision {
+    /// A provider returned a usable answer
+    Winner {
+        provider: String,
+        message: Message,
+        elapsed: Latency,
+    },
+    /// No provider returned a usable answer, but at least one responded
+    Fallback {
+        provider: String,
+        message: Message,
+        response_code: ResponseCode,
+    },
+    /// Every usable answer was rejected by rebinding protection
+    Rejected,
+    /// No provider responded at all
+    AllFailed,
+}
+
+impl RaceDecision {
+    /// The provider whose response is sent, if any
+    fn provider(&self) -> Option<&str> {
+        match self {
+            Self::Winner { provider, .. } | Self::Fallback { provider, .. } => Some(provider),
+            Self::Rejected | Self::AllFailed => None,
+        }
+    }
+}
+
+/// Warns about providers of the same tier whose include rules both match some
+/// domain, so that its queries are raced across both. Catch-all providers, which
+/// match every domain by design, and monitor providers are left out.
+fn warn_overlapping_rules(dns_clients: &[DnsClientEntry], conflict: RuleConflict) {
+    let candidates: Vec<_> = dns_clients
+        .iter()
+        .filter(|entry| !entry.monitor && !entry.domain_rules.0.is_empty())
+        .collect();
+    for (i, a) in candidates.iter().enumerate() {
+        for b in &candidates[i + 1..] {
+            if a.tier != b.tier {
+                continue;
+            }
+            let mut overlaps: Vec<&str> = overlapping_domains(&a.domain_rules.0, &b.domain_rules.0)
+                .chain(overlapping_domains(&b.domain_rules.0, &a.domain_rules.0))
+                .filter(|domain| {
+                    RaceHandler::matches_domain(domain, &a.domain_rules, conflict)
+                        && RaceHandler::matches_domain(domain, &b.domain_rules, conflict)
+                })
+                .collect();
+            overlaps.sort_unstable();
+            overlaps.dedup();
+            if let Some(first) = overlaps.first() {
+                let (a, b) = if a.name <= b.name { (a, b) } else { (b, a) };
+                tracing::warn!(
+                    "Providers {} and {} both include {} domain(s), e.g. {}; queries for them are raced across both",
+                    a.name,
+                    b.name,
+                    overlaps.len(),
+                    first
+                );
+            }
+        }
+    }
+}
+
+/// Domains of `includes` that equal or are subdomains of a domain in `others`
+fn overlapping_domains<'a>(
+    includes: &'a [String],
+    others: &[String],
+) -> impl Iterator<Item = &'a str> {
+    let others: HashSet<&str> = others
+        .iter()
+        .map(|domain| domain.trim_end_matches('.'))
+        .collect();
+    includes.iter().map(String::as_str).filter(move |domain| {
+        let domain = domain.trim_end_matches('.');
+        // Walk the domain and each of its parents
+        std::iter::successors(Some(domain), |name| {
+            name.split_once('.').map(|(_, parent)| parent)
+        })
+        .any(|name| others.contains(name))
+    })
+}
+
+/// The cache of the running handler, if the new configuration can keep it: the cache
+/// settings must be unchanged. Entries of providers that are not kept are purged
+/// once the new handler is in place, see [`SharedHandler::replace`].
+fn reusable_cache(
+    previous: Option<&RaceHandler>,
+    cache_config: &CacheConfig,
+) -> Option<Arc<dyn DnsCache>> {
+    let previous = previous?;
+    if previous.cache_config != *cache_config {
+        return None;
+    }
+    previous.cache.clone()
+}
 
-pub struct RaceHandler {
-    dns_clients: Vec<DnsClientEntry>,
+/// Start delays of the adaptive mode, by index into `clients`. The providers of
+/// `stage` are ordered by average latency, and each starts once the average
+/// latency of the one before it has passed, up to `max_delay`. Providers without
+/// an average yet and monitor providers start at once.
+fn adaptive_delays(
+    clients: &[&DnsClientEntry],
+    race_order: &[(bool, bool, u8, usize)],
+    stage: (bool, bool, u8, usize),
+    max_delay: Duration,
+) -> Vec<Duration> {
+    let mut delays = vec![Duration::ZERO; clients.len()];
+    let mut measured: Vec<(Duration, usize)> = (0..clients.len())
+        .filter(|&index| race_order[index] == stage && !clients[index].monitor)
+        .filter_map(|index| Some((clients[index].latency.get()?, index)))
+        .collect();
+    measured.sort_unstable();
+    for pair in measured.windows(2) {
+        delays[pair[1].1] = pair[0].0.min(max_delay);
+    }
+    delays
 }
 
-impl RaceHandler {
-    pub async fn new(config: &Config) -> Result<Self> {
-        let mut dns_clients = Vec::new();
-        let client_config = Arc::new(create_client_config());
+/// Picks the provider that queries for `query_name` stick to: one of the
+/// providers raced first, chosen by a hash of the name that is stable across
+/// restarts and configuration reloads
+fn sticky_provider(
+    query_name: &str,
+    clients: &[&DnsClientEntry],
+    race_order: &[(bool, bool, u8, usize)],
+) -> Option<usize> {
+    let first = (0..clients.len())
+        .filter(|&index| !clients[index].monitor)
+        .map(|index| race_order[index])
+        .min()?;
+    let mut candidates: Vec<usize> = (0..clients.len())
+        .filter(|&index| !clients[index].monitor && race_order[index] == first)
+        .collect();
+    candidates.sort_by(|&a, &b| clients[a].name.cmp(&clients[b].name));
 
-        let providers = config.get_providers()?;
-        for (addr, hostname, name, domain_rules) in providers {
-            let client = RetryableClient::new(addr, &hostname, client_config.clone()).await?;
-            dns_clients.push(DnsClientEntry {
-                client,
-                name,
-                domain_rules,
-            });
-        }
+    // FNV-1a over the lowercased name
+    let hash = query_name
+        .to_ascii_lowercase()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    Some(candidates[(hash % candidates.len() as u64) as usize])
+}
 
-        Ok(Self { dns_clients })
-    }
+/// Whether a response code is good enough to win the race outright
+fn is_good_response(response_code: ResponseCode) -> bool {
+    response_code != ResponseCode::ServFail && response_code != ResponseCode::NXDomain
+}
 
-    fn matches_domain(query_name: &str, domain_rules: &(Vec<String>, Vec<String>)) -> bool {
-        let (includes, excludes) = domain_rules;
+/// Whether a response may win the race under the given policy
+fn is_winner_candidate(
+    policy: WinnerPolicy,
+    response_code: ResponseCode,
+    message: &Message,
+    min_answers: usize,
+) -> bool {
+    is_good_response(response_code)
+        && (policy == WinnerPolicy::FirstOk || !message.answers().is_empty())
+        && (min_answers == 0 || answers_of_queried_type(message) >= min_answers)
+}
 
-        // If the include list is empty, it means process all domains
-        if includes.is_empty() {
-            return true;
-        }
+/// Answer records of the queried type, e.g. the A records of an A query without
+/// the CNAMEs leading to them; every record counts for ANY queries
+fn answers_of_queried_type(message: &Message) -> usize {
+    let Some(query_type) = message.query().map(|query| query.query_type()) else {
+        return message.answers().len();
+    };
+    message
+        .answers()
+        .iter()
+        .filter(|record| query_type == RecordType::ANY || record.record_type() == query_type)
+        .count()
+}
 
-        let query_name = query_name.trim_end_matches('.');
+/// Picks the response to send when no provider won the race, preferring
+/// a good response without answers over NXDOMAIN over SERVFAIL over
+/// whatever arrived first
+fn select_fallback(mut responses: Vec<ProviderResponse>) -> RaceDecision {
+    let index = responses
+        .iter()
+        .position(|r| is_good_response(r.response_code))
+        .or_else(|| {
+            responses
+                .iter()
+                .position(|r| r.response_code == ResponseCode::NXDomain)
+        })
+        .or_else(|| {
+            responses
+                .iter()
+                .position(|r| r.response_code == ResponseCode::ServFail)
+        })
+        .or_else(|| (!responses.is_empty()).then_some(0));
 
-        // First check if it's in the exclude list
-        for exclude in excludes {
-            if query_name.ends_with(exclude) {
-                return false;
+    match index {
+        Some(index) => {
+            let response = responses.swap_remove(index);
+            RaceDecision::Fallback {
+                provider: response.provider,
+                message: response.message,
+                response_code: response.response_code,
             }
         }
-
-        // Then check if it's in the include list
-        includes
-            .iter()
-            .any(|domain| is_domain_match(query_name, domain))
+        None => RaceDecision::AllFailed,
     }
 }
 
-#[async_trait::async_trait]
-impl RequestHandler for RaceHandler {
-    async fn handle_request<R: ResponseHandler>(
-        &self,
-        request: &Request,
-        mut response_handle: R,
-    ) -> ResponseInfo {
-        let request_info = request.request_info().unwrap();
-        let request_id = request.id();
-        let query = request_info.query;
-        let query_name = query.name().to_string();
-
-        let matching_clients: Vec<_> = self
-            .dns_clients
-            .iter()
-            .filter(|dns_client_entry| {
-                let matches = !dns_client_entry.domain_rules.0.is_empty()
-                    && Self::matches_domain(&query_name, &dns_client_entry.domain_rules);
-                tracing::debug!(
-                    "Provider {} matches domain {}: {}",
-                    dns_client_entry.name,
-                    query_name,
-                    matches
+/// Sends the response for a decision, returning the response code on success.
+/// A background refresh only gets the response code.
+async fn send_decision<R: ResponseHandler>(
+    decision: &RaceDecision,
+    request: &Request,
+    response_handle: &mut R,
+    response: &ResponseConfig,
+    log_winner: bool,
+    refreshing: bool,
+) -> Option<ResponseCode> {
+    if refreshing {
+        return Some(match decision {
+            RaceDecision::Winner { message, .. } => message.response_code(),
+            RaceDecision::Fallback { response_code, .. } => *response_code,
+            RaceDecision::Rejected => ResponseCode::NXDomain,
+            RaceDecision::AllFailed => ResponseCode::ServFail,
+        });
+    }
+    match decision {
+        RaceDecision::Winner {
+            provider,
+            message,
+            elapsed,
+        } => {
+            if let Err(e) = send(message, request, response_handle, response, Some(provider)).await
+            {
+                tracing::error!("Failed to send successful DNS response: {}", e);
+                return None;
+            }
+            STATS.record_win(provider);
+            if log_winner {
+                tracing::info!(
+                    "✔ {}: {} | {}",
+                    provider,
+                    elapsed,
+                    format_answers(message.query(), message.answers())
                 );
-                matches
-            })
-            .collect();
-
-        tracing::debug!(
-            "Found {} matching providers for domain {}",
-            matching_clients.len(),
-            query_name
-        );
+            }
+            Some(message.header().response_code())
+        }
+        RaceDecision::Fallback {
+            provider,
+            message,
+            response_code,
+        } => {
+            let fallbacks = STATS.record_fallback();
+            tracing::warn!(
+                "● Fallback response {}for {} from {} (fallbacks: {})",
+                format_response_code(*response_code),
+                message
+                    .query()
+                    .map_or(String::from("?"), |q| q.name().to_string()),
+                provider,
+                fallbacks
+            );
+            if let Err(e) = send(message, request, response_handle, response, Some(provider)).await
+            {
+                tracing::error!("Failed to send successful DNS response: {}", e);
+                return None;
+            }
+            Some(*response_code)
+        }
+        RaceDecision::Rejected => {
+            tracing::info!("● Rebinding protection response (NXDomain)");
+            let message = Message::error_msg(request.id(), OpCode::Query, ResponseCode::NXDomain);
+            if let Err(e) = send(&message, request, response_handle, response, None).await {
+                tracing::error!("Failed to send NXDomain DNS response: {}", e);
+                return None;
+            }
+            Some(ResponseCode::NXDomain)
+        }
+        RaceDecision::AllFailed => {
+            tracing::error!("✘ All DNS queries failed");
+            let message = Message::error_msg(request.id(), OpCode::Query, ResponseCode::ServFail);
+            if let Err(e) = send(&message, request, response_handle, response, None).await {
+                tracing::error!("Failed to send ServFail DNS response: {}", e);
+                return None;
+            }
+            Some(ResponseCode::ServFail)
+        }
+    }
+}
 
-        let clients_to_use = if matching_clients.is_empty() {
-            self.dns_clients
-                .iter()
-                .filter(|dns_client_entry| dns_client_entry.domain_rules.0.is_empty())
-                .collect::<Vec<_>>()
-        } else {
-            tracing::info!("Using specific DNS provider for domain: {}", query_name);
-            matching_clients
-        };
+/// Synthesizes the answer for a query matched by a blocklist, or for every query
+/// in maintenance mode
+fn block_response(
+    action: BlockAction,
+    custom_ip: &[IpAddr],
+    ttl: u32,
+    query: &Query,
+    request_id: u16,
+    recursion_desired: bool,
+) -> Message {
+    let mut message = Message::new();
+    message
+        .set_id(request_id)
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(recursion_desired)
+        .set_recursion_available(true)
+        .add_query(query.clone());
 
-        if clients_to_use.is_empty() {
-            tracing::error!("No DNS provider available for domain: {}", query_name);
-            return create_servfail_response(request_id);
+    let ips = match action {
+        BlockAction::Nxdomain => {
+            message.set_response_code(ResponseCode::NXDomain);
+            return message;
         }
+        BlockAction::Refused => {
+            message.set_response_code(ResponseCode::Refused);
+            return message;
+        }
+        BlockAction::Servfail => {
+            message.set_response_code(ResponseCode::ServFail);
+            return message;
+        }
+        BlockAction::Nodata => return message,
+        BlockAction::ZeroIp => &[
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        ][..],
+        BlockAction::CustomIp => custom_ip,
+    };
 
-        let mut futures = clients_to_use
-            .iter()
-            .map(move |dns_client_entry| {
-                let start = Instant::now();
-                let client = dns_client_entry.client.clone();
-                let name_clone = Name::from(query.name());
-                let query_type = query.query_type();
-                let query_class = query.query_class();
-                let name = dns_client_entry.name.clone();
-
-                Box::pin(async move {
-                    match client.query(name_clone, query_class, query_type).await {
-                        Ok(response) => Ok((response, start.elapsed(), name)),
-                        Err(e) => Err((e, start.elapsed(), name)),
-                    }
-                })
-            })
-            .collect::<FuturesUnordered<_>>();
+    for ip in ips {
+        let rdata = match (ip, query.query_type()) {
+            (IpAddr::V4(ip), RecordType::A) => RData::A(A(*ip)),
+            (IpAddr::V6(ip), RecordType::AAAA) => RData::AAAA(AAAA(*ip)),
+            _ => continue,
+        };
+        message.add_answer(Record::from_rdata(query.name().clone(), ttl, rdata));
+    }
+    message
+}
 
-        let mut final_response_code = ResponseCode::ServFail;
-        let mut responses: Vec<(ResponseCode, Message, String, Duration)> = Vec::new();
-        let mut has_sent_response = false;
+/// Answers the CHAOS class identification names locally (RFC 4892), so they are
+/// neither forwarded upstream nor answered with the real version
+fn chaos_response(chaos: &ChaosConfig, query: &Query, recursion_desired: bool) -> Option<Message> {
+    if query.query_class() != DNSClass::CH {
+        return None;
+    }
+    let name = query.name().to_lowercase().to_ascii();
+    let text = match name.trim_end_matches('.') {
+        "version.bind" | "version.server" => &chaos.version,
+        "hostname.bind" | "id.server" => &chaos.hostname,
+        _ => return None,
+    };
 
-        while let Some(result) = futures.next().await {
-            match result {
-                Ok((response, elapsed, name)) => {
-                    let response_code = response.header().response_code();
-                    let mut message = response.into_message();
-                    message.set_id(request_id);
+    let mut message = Message::new();
+    message
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_authoritative(true)
+        .set_recursion_desired(recursion_desired)
+        .set_recursion_available(true)
+        .add_query(query.clone());
+    // Other types for these names get an empty answer
+    if matches!(query.query_type(), RecordType::TXT | RecordType::ANY) {
+        let mut record = Record::from_rdata(
+            query.name().clone(),
+            0,
+            RData::TXT(TXT::new(vec![text.clone()])),
+        );
+        record.set_dns_class(DNSClass::CH);
+        message.add_answer(record);
+    }
+    Some(message)
+}
 
-                    responses.push((response_code, message.clone(), name.clone(), elapsed));
+/// Answers special-use names (RFC 6761) locally: `localhost` with the loopback
+/// addresses, and the configured names with NXDOMAIN, so they never leak upstream
+fn special_use_response(
+    special_use: &SpecialUseConfig,
+    query: &Query,
+    recursion_desired: bool,
+) -> Option<Message> {
+    if query.query_class() != DNSClass::IN {
+        return None;
+    }
+    let name = query.name().to_lowercase().to_ascii();
+    let name = name.trim_end_matches('.');
+    let localhost = special_use.localhost && is_domain_match(name, "localhost");
+    if !localhost
+        && !special_use
+            .names
+            .iter()
+            .any(|domain| is_domain_match(name, domain.trim_end_matches('.')))
+    {
+        return None;
+    }
 
-                    if !has_sent_response {
-                        if response_code != ResponseCode::ServFail
-                            && response_code != ResponseCode::NXDomain
-                        {
-                            let builder = MessageResponseBuilder::from_message_request(request);
-                            let response = builder.build(
-                                *message.header(),
-                                message.answers(),
-                                message.name_servers(),
-                                None,
-                                message.additionals(),
-                            );
+    let mut message = Message::new();
+    message
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_authoritative(true)
+        .set_recursion_desired(recursion_desired)
+        .set_recursion_available(true)
+        .add_query(query.clone());
+    if !localhost {
+        message.set_response_code(ResponseCode::NXDomain);
+        return Some(message);
+    }
+    // Other types for localhost get an empty answer
+    let rdata = match query.query_type() {
+        RecordType::A => RData::A(A(Ipv4Addr::LOCALHOST)),
+        RecordType::AAAA => RData::AAAA(AAAA(Ipv6Addr::LOCALHOST)),
+        _ => return Some(message),
+    };
+    message.add_answer(Record::from_rdata(
+        query.name().clone(),
+        LOCALHOST_TTL,
+        rdata,
+    ));
+    Some(message)
+}
 
-                            if let Err(e) = response_handle.send_response(response).await {
-                                tracing::error!("Failed to send successful DNS response: {}", e);
-                                has_sent_response = false;
-                            } else {
-                                tracing::info!(
-                                    "✔ {}: {:?} | {}",
-                                    name,
-                                    elapsed,
-                                    format_answers(message.query(), message.answers())
-                                );
-                                final_response_code = response_code;
-                                has_sent_response = true;
-                            }
-                        } else {
-                            tracing::info!(
-                                "◼︎ {}: {}{:?} | {}",
-                                name,
-                                format_response_code(response_code),
-                                elapsed,
-                                format_answers(message.query(), message.answers())
-                            );
-                        }
-                    } else {
-                        tracing::info!(
-                            "◼︎ {}: {}{:?} | {}",
-                            name,
-                            format_response_code(response_code),
-                            elapsed,
-                            format_answers(message.query(), message.answers())
-                        );
-                    }
-                }
-                Err((e, elapsed, name)) => {
-                    tracing::error!("Query failed: {:?}, {:?}, <{}>", e, elapsed, name);
-                }
-            }
+/// Builds the client response from an upstream message and sends it.
+/// With `minimal`, only the answer section is passed through. `source` names
+/// where the answer came from and is reported to the client in the debug EDNS
+/// option when enabled.
+async fn send<R: ResponseHandler>(
+    message: &Message,
+    request: &Request,
+    response_handle: &mut R,
+    response: &ResponseConfig,
+    source: Option<&str>,
+) -> std::io::Result<ResponseInfo> {
+    let (name_servers, additionals) = if response.minimal {
+        (&[][..], &[][..])
+    } else {
+        (message.name_servers(), message.additionals())
+    };
+    let mut edns = source.and_then(|source| debug_edns(request, response.debug_provider, source));
+    // RD is copied from the query (RFC 1035 4.1.1), whatever the upstream was asked
+    let mut header = *message.header();
+    header.set_recursion_desired(request.recursion_desired());
+    let sorted;
+    let answers = if response.sort_answers {
+        sorted = sort_answers(message.answers());
+        &sorted[..]
+    } else {
+        message.answers()
+    };
+    let build = |edns: Option<&Edns>, truncated: bool| {
+        let mut builder = MessageResponseBuilder::from_message_request(request);
+        if let Some(edns) = edns {
+            builder.edns(edns.clone());
         }
+        if truncated {
+            let mut header = header;
+            header.set_truncated(true);
+            builder.build(header, &[][..], &[][..], None, &[][..])
+        } else {
+            builder.build(header, answers, name_servers, None, additionals)
+        }
+    };
 
-        if !has_sent_response && !responses.is_empty() {
-            let selected_response = responses
-                .iter()
-                .find(|(code, ..)| *code == ResponseCode::NXDomain)
-                .or_else(|| {
-                    responses
-                        .iter()
-                        .find(|(code, ..)| *code == ResponseCode::ServFail)
-                })
-                .or_else(|| responses.first())
-                .unwrap();
-
-            let (response_code, message, name, _) = selected_response;
-            tracing::info!(
-                "● Fallback response {}from {}",
-                format_response_code(*response_code),
-                name
-            );
-
-            let builder = MessageResponseBuilder::from_message_request(request);
-            let response = builder.build(
-                *message.header(),
-                message.answers(),
-                message.name_servers(),
-                None,
-                message.additionals(),
+    // Measuring encodes the response once more, so it is only done when a limit is set
+    let size_limit = response_size_limit(request, response);
+    let mut truncated = false;
+    if let Some(size_limit) = size_limit {
+        let mut encoded = Vec::new();
+        build(edns.as_ref(), false)
+            .destructive_emit(&mut BinEncoder::new(&mut encoded))
+            .map_err(std::io::Error::other)?;
+        if encoded.len() > usize::from(size_limit) {
+            // Like a truncated upstream answer, the client retries over TCP
+            tracing::debug!(
+                "Truncating {} byte response to {} over {} (limit {})",
+                encoded.len(),
+                request.src(),
+                request.protocol(),
+                size_limit
             );
-
-            if let Err(e) = response_handle.send_response(response).await {
-                tracing::error!("Failed to send successful DNS response: {}", e);
-                has_sent_response = false;
-            } else {
-                final_response_code = *response_code;
-                has_sent_response = true;
-            }
+            truncated = true;
         }
+    }
 
-        if has_sent_response {
-            let mut header = Header::new();
-            header.set_id(request_id);
-            header.set_message_type(MessageType::Response);
-            header.set_op_code(OpCode::Query);
-            header.set_response_code(final_response_code);
-            ResponseInfo::from(header)
+    if let Some(block_size) = padding_block_size(request, response)
+        && let Some(request_edns) = request.edns()
+    {
+        let edns = edns.get_or_insert_with(|| response_edns(request_edns));
+        let mut unpadded = Vec::new();
+        build(Some(edns), truncated)
+            .destructive_emit(&mut BinEncoder::new(&mut unpadded))
+            .map_err(std::io::Error::other)?;
+        // The padding option itself adds a 4 byte header
+        let length = unpadded.len() + 4;
+        let block_size = usize::from(block_size);
+        // Padding must not push a response over the size limit of its transport,
+        // nor a UDP response over the client's payload size
+        let max_length = size_limit.unwrap_or(if request.protocol().is_datagram() {
+            edns.max_payload()
         } else {
-            tracing::error!("✘ All DNS queries failed");
-            let mut header = Header::new();
-            header.set_id(request_id);
-            header.set_message_type(MessageType::Response);
-            header.set_op_code(OpCode::Query);
-            header.set_response_code(ResponseCode::ServFail);
-
-            let builder = MessageResponseBuilder::from_message_request(request);
-            let response = builder.build(
-                header,
-                vec![], // empty answers
-                vec![], // empty name servers
-                None,   // empty zone
-                vec![], // empty additionals
-            );
-            if let Err(e) = response_handle.send_response(response).await {
-                tracing::error!("Failed to send ServFail DNS response: {}", e);
-            }
+            u16::MAX
+        });
+        let padding = ((block_size - length % block_size) % block_size)
+            .min(usize::from(max_length).saturating_sub(length));
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(PADDING_OPTION_CODE, vec![0; padding]));
+    }
 
-            ResponseInfo::from(header)
+    // The request keeps no copy of the bytes it was decoded from, so the query is
+    // captured as encoded again; name compression and the like may differ
+    if let Some(capture) = capture::sampled() {
+        let mut response = Vec::new();
+        let encoded = request.to_bytes().and_then(|query| {
+            build(edns.as_ref(), truncated)
+                .destructive_emit(&mut BinEncoder::new(&mut response))?;
+            Ok(query)
+        });
+        match encoded {
+            Ok(query) => capture.record(request.src(), query, response),
+            Err(e) => tracing::error!("Failed to encode packets for capture: {}", e),
         }
     }
+
+    response_handle
+        .send_response(build(edns.as_ref(), truncated))
+        .await
 }
 
-fn create_client_config() -> ClientConfig {
-    let mut config = client_config();
+fn create_client_config(roots: TlsRoots) -> Result<ClientConfig> {
+    let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(root_store(roots)?)
+        .with_no_client_auth();
     config.alpn_protocols = vec![ALPN_H2.to_vec()];
-    config
+    Ok(config)
+}
+
+/// Loads the trusted root certificates, failing if there are none
+fn root_store(roots: TlsRoots) -> Result<RootCertStore> {
+    let mut store = RootCertStore::empty();
+    match roots {
+        TlsRoots::Webpki => store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        TlsRoots::System => {
+            let native = rustls_native_certs::load_native_certs();
+            for error in &native.errors {
+                tracing::warn!("Error loading system root certificates: {}", error);
+            }
+            let (added, ignored) = store.add_parsable_certificates(native.certs);
+            if ignored > 0 {
+                tracing::warn!("Ignored {} unparsable system root certificates", ignored);
+            }
+            tracing::info!(
+                "Loaded {} root certificates from the system trust store",
+                added
+            );
+        }
+    }
+    if store.is_empty() {
+        anyhow::bail!(
+            "No trusted root certificates found in the {} store; install the CA certificates \
+             (e.g. the ca-certificates package) or set [tls] roots = \"webpki\"",
+            match roots {
+                TlsRoots::Webpki => "bundled",
+                TlsRoots::System => "system",
+            }
+        );
+    }
+    Ok(store)
 }
 
 fn format_answers(
@@ -316,6 +2182,125 @@ fn format_answers(
     format!("{query_info} → {result}")
 }
 
+/// Removes records of the given types from every section of the message
+fn drop_record_types(message: &mut Message, drop_types: &[RecordType]) {
+    let keep = |record: &Record| !drop_types.contains(&record.record_type());
+    message.answers_mut().retain(keep);
+    message.name_servers_mut().retain(keep);
+    message.additionals_mut().retain(keep);
+}
+
+/// Appends the records of the queried type at the end of the CNAME chain that
+/// `other` has and `message` does not. Both answers must follow the same chain,
+/// otherwise the owner would get two CNAMEs (RFC 2181 10.1) and `message` is
+/// left unchanged; returns whether it was merged.
+fn merge_answers(
+    message: &mut Message,
+    other: &Message,
+    query_name: &Name,
+    query_type: RecordType,
+) -> bool {
+    let owner = final_owner(message.answers(), query_name, query_type);
+    if final_owner(other.answers(), query_name, query_type) != owner {
+        return false;
+    }
+    let is_final = |record: &Record| *record.name() == owner && record.record_type() == query_type;
+    let chain: Vec<_> = message.answers().iter().filter(|r| !is_final(r)).collect();
+    let other_chain: Vec<_> = other.answers().iter().filter(|r| !is_final(r)).collect();
+    let same_chain = chain.len() == other_chain.len()
+        && chain
+            .iter()
+            .all(|record| other_chain.iter().any(|other| same_record(record, other)));
+    if !same_chain {
+        return false;
+    }
+
+    for record in other.answers().iter().filter(|r| is_final(r)) {
+        if !message
+            .answers()
+            .iter()
+            .any(|existing| same_record(existing, record))
+        {
+            message.add_answer(record.clone());
+        }
+    }
+    true
+}
+
+/// The name the CNAME chain in `answers` leads to from `query_name`
+fn final_owner(answers: &[Record], query_name: &Name, query_type: RecordType) -> Name {
+    let mut owner = query_name.clone();
+    if query_type == RecordType::CNAME {
+        return owner;
+    }
+    // Bounded by the number of answers, so a CNAME loop ends too
+    for _ in 0..answers.len() {
+        let target = answers.iter().find_map(|record| match record.data() {
+            RData::CNAME(cname) if *record.name() == owner => Some(cname.0.clone()),
+            _ => None,
+        });
+        match target {
+            Some(target) => owner = target,
+            None => break,
+        }
+    }
+    owner
+}
+
+/// Whether two records are equal in name, type, class and data, ignoring the TTL
+fn same_record(a: &Record, b: &Record) -> bool {
+    a.name() == b.name()
+        && a.record_type() == b.record_type()
+        && a.dns_class() == b.dns_class()
+        && a.data() == b.data()
+}
+
+/// Shuffles the records of the queried type in place, leaving any other
+/// records (e.g. a leading CNAME chain) where they are
+fn shuffle_answers(answers: &mut [Record], query_type: RecordType) {
+    let positions: Vec<usize> = answers
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| record.record_type() == query_type)
+        .map(|(i, _)| i)
+        .collect();
+    if positions.len() < 2 {
+        return;
+    }
+
+    let mut records: Vec<Record> = positions.iter().map(|&i| answers[i].clone()).collect();
+    records.shuffle(&mut rand::rng());
+    for (i, record) in positions.into_iter().zip(records) {
+        answers[i] = record;
+    }
+}
+
+/// Orders answers by record type and then by their wire format data. The records
+/// of each name stay together and names keep their order, so a CNAME chain is
+/// still answered from the queried name on.
+fn sort_answers(answers: &[Record]) -> Vec<Record> {
+    let mut names: Vec<&Name> = Vec::new();
+    let mut keyed: Vec<_> = answers
+        .iter()
+        .map(|record| {
+            let name = match names.iter().position(|name| *name == record.name()) {
+                Some(position) => position,
+                None => {
+                    names.push(record.name());
+                    names.len() - 1
+                }
+            };
+            let data = record.data().to_bytes().unwrap_or_default();
+            ((name, u16::from(record.record_type()), data), record)
+        })
+        .collect();
+    keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+    keyed
+        .into_iter()
+        .map(|(_, record)| record.clone())
+        .collect()
+}
+
 fn format_response_code(code: ResponseCode) -> String {
     if code == ResponseCode::NoError {
         String::new()
@@ -333,6 +2318,113 @@ fn create_servfail_response(request_id: u16) -> ResponseInfo {
     ResponseInfo::from(header)
 }
 
+/// An OPT record carrying the answer's source in the debug option, if the
+/// client used EDNS and the debug policy allows it
+fn debug_edns(request: &Request, policy: DebugProvider, source: &str) -> Option<Edns> {
+    let request_edns = request.edns()?;
+    let requested = request_edns
+        .option(EdnsCode::from(DEBUG_OPTION_CODE))
+        .is_some();
+    let enabled = match policy {
+        DebugProvider::Off => false,
+        DebugProvider::OnRequest => requested,
+        DebugProvider::Always => true,
+    };
+    if !enabled {
+        return None;
+    }
+
+    let mut edns = response_edns(request_edns);
+    edns.options_mut().insert(EdnsOption::Unknown(
+        DEBUG_OPTION_CODE,
+        source.as_bytes().to_vec(),
+    ));
+    Some(edns)
+}
+
+/// EDNS for a response to a query with EDNS, without any options
+fn response_edns(request_edns: &Edns) -> Edns {
+    let mut edns = Edns::new();
+    edns.set_max_payload(request_edns.max_payload().max(512))
+        .set_dnssec_ok(request_edns.flags().dnssec_ok);
+    edns
+}
+
+/// The largest response the client can receive, if a limit is configured for its
+/// transport: over UDP the payload size it advertises in EDNS (512 bytes without
+/// EDNS, RFC 1035 4.2.1), capped at `max_udp_size`; over stream transports
+/// `max_tcp_size`. Without one, hickory-server's own limit applies.
+fn response_size_limit(request: &Request, response: &ResponseConfig) -> Option<u16> {
+    if request.protocol().is_datagram() {
+        let advertised = request
+            .edns()
+            .map_or(512, |edns| edns.max_payload().max(512));
+        Some(advertised.min(response.max_udp_size?))
+    } else {
+        response.max_tcp_size
+    }
+}
+
+/// The block size to pad the response to, if padding is configured, the client asked
+/// for it and the transport is encrypted (or padding unencrypted responses is enabled)
+fn padding_block_size(request: &Request, response: &ResponseConfig) -> Option<u16> {
+    let block_size = response.padding_block_size.filter(|&size| size > 0)?;
+    let requested = request.edns()?.option(EdnsCode::Padding).is_some();
+    (requested && (request.protocol().is_encrypted() || response.pad_unencrypted))
+        .then_some(block_size)
+}
+
+/// Emits one access log event per query; see [`logger::ACCESS_TARGET`]. With
+/// `anonymize`, the client address is truncated to its /24 or /56 network.
+fn log_access(
+    request: &Request,
+    info: &ResponseInfo,
+    source: Option<&str>,
+    elapsed: Duration,
+    anonymize: bool,
+) {
+    let client = match request.src().ip().to_canonical() {
+        client @ IpAddr::V4(_) if anonymize => cache::mask(client, 24),
+        client @ IpAddr::V6(_) if anonymize => cache::mask(client, 56),
+        client => client,
+    };
+    let (name, query_type) = match request.request_info() {
+        Ok(request_info) => (
+            request_info.query.name().to_string(),
+            request_info.query.query_type().to_string(),
+        ),
+        Err(_) => ("-".to_string(), "-".to_string()),
+    };
+    tracing::info!(
+        target: logger::ACCESS_TARGET,
+        client = %client,
+        protocol = %request.protocol(),
+        name = %name,
+        qtype = %query_type,
+        source = %source.unwrap_or("-"),
+        rcode = ?info.response_code(),
+        latency_ms = elapsed.as_millis() as u64,
+    );
+}
+
+/// Loopback, RFC 1918, link-local, and IPv6 unique-local addresses
+fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_ip(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.is_unspecified()
+            }
+        },
+    }
+}
+
 #[inline]
 fn is_domain_match(query: &str, pattern: &str) -> bool {
     if query == pattern {
@@ -348,3 +2440,323 @@ fn is_domain_match(query: &str, pattern: &str) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::rdata::{CNAME, opt::ClientSubnet};
+    use std::{net::SocketAddr, sync::Mutex};
+
+    /// A provider that is never reached: the tests answer from the cache or local records
+    const PROVIDER: &str = r#"
+        [providers.test]
+        addr = "127.0.0.1:9"
+        hostname = "test.invalid"
+        reuse = false
+    "#;
+
+    /// Response handle that keeps the encoded response
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Option<Vec<u8>>>>);
+
+    impl Capture {
+        fn message(&self) -> Message {
+            let bytes = self.0.lock().unwrap().clone().expect("no response sent");
+            Message::from_vec(&bytes).unwrap()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ResponseHandler for Capture {
+        async fn send_response<'a>(
+            &mut self,
+            response: MessageResponse<
+                '_,
+                'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+            >,
+        ) -> std::io::Result<ResponseInfo> {
+            let mut bytes = Vec::new();
+            let info = response
+                .destructive_emit(&mut BinEncoder::new(&mut bytes))
+                .map_err(std::io::Error::other)?;
+            *self.0.lock().unwrap() = Some(bytes);
+            Ok(info)
+        }
+    }
+
+    fn config(config: &str) -> Config {
+        toml::from_str(&format!("{PROVIDER}\n{config}")).unwrap()
+    }
+
+    async fn handler(config: &str) -> Arc<RaceHandler> {
+        Arc::new(RaceHandler::new(&self::config(config), None).await.unwrap())
+    }
+
+    fn query(id: u16, name: &str, query_type: RecordType) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(id)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), query_type));
+        message
+    }
+
+    fn request(message: &Message, src: SocketAddr) -> Request {
+        let bytes = message.to_vec().unwrap();
+        Request::new(
+            MessageRequest::from_bytes(&bytes).unwrap(),
+            src,
+            Protocol::Udp,
+        )
+    }
+
+    /// Sends a request through the handler and returns the response and its source
+    async fn resolve(handler: &Arc<RaceHandler>, request: &Request) -> (Message, Option<String>) {
+        let capture = Capture::default();
+        let mut source = None;
+        handler
+            .resolve(request, capture.clone(), &mut source, false)
+            .await;
+        (capture.message(), source)
+    }
+
+    fn a_records(message: &Message) -> Vec<Ipv4Addr> {
+        message
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                RData::A(a) => Some(a.0),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn malformed_question_count_is_formerr() {
+        let handler = handler("").await;
+        let src = "192.0.2.53:53000".parse().unwrap();
+
+        let mut no_question = Message::new();
+        no_question.set_id(4321).set_recursion_desired(true);
+        let mut two_questions = query(4322, "a.example.", RecordType::A);
+        two_questions.add_query(Query::query(
+            Name::from_ascii("b.example.").unwrap(),
+            RecordType::A,
+        ));
+
+        for message in [no_question, two_questions] {
+            let capture = Capture::default();
+            let info = handler
+                .handle_request(&request(&message, src), capture.clone())
+                .await;
+            let response = capture.message();
+            assert_eq!(response.id(), message.id());
+            assert_eq!(response.response_code(), ResponseCode::FormErr);
+            assert_eq!(info.response_code(), ResponseCode::FormErr);
+        }
+    }
+
+    /// An A query in wire format, encoded by hand so its name may break the RFC 1035 limits
+    fn wire_query(lengths: &[usize]) -> Vec<u8> {
+        let mut bytes = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for &length in lengths {
+            bytes.push(length as u8);
+            bytes.extend(std::iter::repeat_n(b'a', length));
+        }
+        bytes.extend([0, 0, 1, 0, 1]);
+        bytes
+    }
+
+    #[test]
+    fn oversized_names_are_refused_by_the_decoder() {
+        // 63 + 63 + 63 + 61 octets and three dots, the longest name allowed
+        assert!(MessageRequest::from_bytes(&wire_query(&[63, 63, 63, 61])).is_ok());
+        // A 64 octet label and a 254 octet name never reach the handler: the
+        // request decoder refuses them, and hickory-server answers FORMERR
+        assert!(MessageRequest::from_bytes(&wire_query(&[64, 1])).is_err());
+        assert!(MessageRequest::from_bytes(&wire_query(&[63, 63, 63, 62])).is_err());
+        // Nor can such a name be built any other way
+        assert!(Name::from_labels([64, 1].map(|length| vec![b'a'; length])).is_err());
+        assert!(Name::from_labels([63, 63, 63, 62].map(|length| vec![b'a'; length])).is_err());
+    }
+
+    /// An answer to `www.example.` A through a CNAME to `target`
+    fn cname_answer(target: &str, addrs: &[[u8; 4]]) -> Message {
+        let target = Name::from_ascii(target).unwrap();
+        let mut message = query(1, "www.example.", RecordType::A);
+        message.add_answer(Record::from_rdata(
+            Name::from_ascii("www.example.").unwrap(),
+            300,
+            RData::CNAME(CNAME(target.clone())),
+        ));
+        for &addr in addrs {
+            message.add_answer(Record::from_rdata(
+                target.clone(),
+                60,
+                RData::A(A::from(Ipv4Addr::from(addr))),
+            ));
+        }
+        message
+    }
+
+    #[test]
+    fn merge_keeps_the_winner_when_cname_chains_differ() {
+        let name = Name::from_ascii("www.example.").unwrap();
+        let mut message = cname_answer("a.cdn.example.", &[[192, 0, 2, 1]]);
+        let other = cname_answer("b.cdn.example.", &[[198, 51, 100, 1]]);
+
+        assert!(!merge_answers(&mut message, &other, &name, RecordType::A));
+        assert_eq!(
+            message.answers(),
+            cname_answer("a.cdn.example.", &[[192, 0, 2, 1]]).answers()
+        );
+    }
+
+    #[test]
+    fn merge_adds_final_records_of_the_same_cname_chain() {
+        let name = Name::from_ascii("www.example.").unwrap();
+        let mut message = cname_answer("a.cdn.example.", &[[192, 0, 2, 1]]);
+        let other = cname_answer("a.cdn.example.", &[[192, 0, 2, 1], [192, 0, 2, 2]]);
+
+        assert!(merge_answers(&mut message, &other, &name, RecordType::A));
+        let cnames = message
+            .answers()
+            .iter()
+            .filter(|record| record.record_type() == RecordType::CNAME)
+            .count();
+        assert_eq!(cnames, 1);
+        assert_eq!(
+            a_records(&message),
+            [Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn cached_answer_is_shared_across_client_subnets() {
+        let handler = handler("[cache]\npositive = true").await;
+        let name = Name::from_ascii("geo.example.").unwrap();
+
+        // An answer scoped to the first subnet, as a provider may tag it
+        let mut answer = query(0, "geo.example.", RecordType::A);
+        answer
+            .set_message_type(MessageType::Response)
+            .add_answer(Record::from_rdata(
+                name.clone(),
+                300,
+                RData::A(A::new(192, 0, 2, 1)),
+            ));
+        let mut edns = Edns::new();
+        edns.options_mut()
+            .insert(EdnsOption::Subnet(ClientSubnet::new(
+                Ipv4Addr::new(198, 51, 100, 0).into(),
+                24,
+                24,
+            )));
+        answer.set_edns(edns);
+        let key = CacheKey {
+            name: LowerName::from(&name),
+            query_type: RecordType::A,
+            query_class: DNSClass::IN,
+        };
+        handler
+            .cache
+            .as_ref()
+            .unwrap()
+            .insert(key, answer, Duration::from_secs(300), "test")
+            .await;
+
+        for (id, subnet) in [(1, [198, 51, 100, 0]), (2, [203, 0, 113, 0])] {
+            let mut message = query(id, "geo.example.", RecordType::A);
+            let mut edns = Edns::new();
+            edns.options_mut()
+                .insert(EdnsOption::Subnet(ClientSubnet::new(
+                    Ipv4Addr::from(subnet).into(),
+                    24,
+                    0,
+                )));
+            message.set_edns(edns);
+            let request = request(&message, "192.0.2.53:53000".parse().unwrap());
+
+            let (response, source) = resolve(&handler, &request).await;
+            assert_eq!(response.id(), id);
+            assert_eq!(source.as_deref(), Some("cache"));
+            assert_eq!(a_records(&response), [Ipv4Addr::new(192, 0, 2, 1)]);
+        }
+    }
+
+    /// Local records answering swap.test. with the two given addresses
+    fn swap_records(a: &str, b: &str) -> String {
+        [a, b]
+            .map(|addr| {
+                format!(
+                    "[[local_records]]\nname = \"swap.test\"\ntype = \"A\"\nvalue = \"{addr}\"\n"
+                )
+            })
+            .concat()
+    }
+
+    /// Queries swap.test. through the shared handler, returning the sorted A records
+    async fn ask_swap(shared: &SharedHandler, id: u16) -> Vec<Ipv4Addr> {
+        let capture = Capture::default();
+        let message = query(id, "swap.test.", RecordType::A);
+        let src = "192.0.2.53:53000".parse().unwrap();
+        shared
+            .handle_request(&request(&message, src), capture.clone())
+            .await;
+        let mut answers = a_records(&capture.message());
+        answers.sort();
+        answers
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn replace_answers_each_query_from_one_handler() {
+        let old = swap_records("10.0.0.1", "10.0.0.2");
+        let new = swap_records("10.0.1.1", "10.0.1.2");
+        let shared = SharedHandler::new(RaceHandler::new(&config(&old), None).await.unwrap());
+
+        let replacer = tokio::spawn({
+            let shared = shared.clone();
+            async move {
+                for i in 0..20 {
+                    let config = if i % 2 == 0 { &new } else { &old };
+                    let handler = RaceHandler::new(&self::config(config), Some(&shared.current()))
+                        .await
+                        .unwrap();
+                    shared.replace(handler).await;
+                    // Let queries run against each handler for a while
+                    tokio::time::sleep(Duration::from_millis(2)).await;
+                }
+            }
+        });
+
+        let old_answers = [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)];
+        let new_answers = [Ipv4Addr::new(10, 0, 1, 1), Ipv4Addr::new(10, 0, 1, 2)];
+        let (mut seen_old, mut seen_new) = (false, false);
+        let mut id = 0;
+        while !replacer.is_finished() {
+            let batch: Vec<_> = (0..8)
+                .map(|_| {
+                    id += 1;
+                    let shared = shared.clone();
+                    tokio::spawn(async move { ask_swap(&shared, id).await })
+                })
+                .collect();
+            for query in batch {
+                let answers = query.await.unwrap();
+                assert!(
+                    answers == old_answers || answers == new_answers,
+                    "mixed answer {answers:?}"
+                );
+                seen_old |= answers == old_answers;
+                seen_new |= answers == new_answers;
+            }
+        }
+        replacer.await.unwrap();
+        assert!(seen_old && seen_new, "queries did not overlap the swaps");
+        assert_eq!(ask_swap(&shared, 0).await, old_answers);
+    }
+}
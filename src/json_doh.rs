@@ -0,0 +1,141 @@
+use anyhow::{Context, Result, bail};
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{DNSClass, Name, RData, Record, RecordType},
+    serialize::txt::RDataParser,
+};
+use rustls::ClientConfig;
+use serde::Deserialize;
+use std::{net::SocketAddr, str::FromStr};
+
+/// Client for the JSON DoH API (`application/dns-json`) offered by e.g. Google at `/resolve`
+pub struct JsonDohClient {
+    http: reqwest::Client,
+    url: String,
+}
+
+/// A JSON DoH response; field names follow the Google/Cloudflare API
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonResponse {
+    status: u16,
+    #[serde(default, rename = "TC")]
+    truncated: bool,
+    #[serde(default, rename = "RD")]
+    recursion_desired: bool,
+    #[serde(default, rename = "RA")]
+    recursion_available: bool,
+    #[serde(default, rename = "AD")]
+    authentic_data: bool,
+    #[serde(default, rename = "CD")]
+    checking_disabled: bool,
+    #[serde(default)]
+    answer: Vec<JsonRecord>,
+    #[serde(default)]
+    authority: Vec<JsonRecord>,
+    #[serde(default)]
+    additional: Vec<JsonRecord>,
+}
+
+#[derive(Deserialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    record_type: u16,
+    #[serde(rename = "TTL", default)]
+    ttl: u32,
+    data: String,
+}
+
+impl JsonDohClient {
+    /// Creates a client that connects to `addr` while using `hostname` for TLS and the URL.
+    /// It trusts the root certificates of `client_config`.
+    pub fn new(
+        addr: SocketAddr,
+        hostname: &str,
+        path: &str,
+        client_config: &ClientConfig,
+    ) -> Result<Self> {
+        // The wire format connections negotiate HTTP/2 only, which this client does not speak
+        let mut tls = client_config.clone();
+        tls.alpn_protocols.clear();
+        let http = reqwest::Client::builder()
+            .use_preconfigured_tls(tls)
+            .resolve(hostname, addr)
+            .build()
+            .context("Failed to build JSON DoH client")?;
+        Ok(Self {
+            http,
+            url: format!("https://{}:{}{}", hostname, addr.port(), path),
+        })
+    }
+
+    /// Sends a query and converts the JSON answer into a DNS message.
+    /// Responses larger than `max_size` bytes are rejected.
+    pub async fn query(
+        &self,
+        name: &Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        max_size: usize,
+    ) -> Result<Message> {
+        if query_class != DNSClass::IN {
+            bail!("the JSON API only supports class IN, not {query_class}");
+        }
+
+        let response = self
+            .http
+            .get(&self.url)
+            .query(&[
+                ("name", name.to_ascii()),
+                ("type", u16::from(query_type).to_string()),
+            ])
+            .header(reqwest::header::ACCEPT, "application/dns-json")
+            .send()
+            .await?
+            .error_for_status()?;
+        let body = response.bytes().await?;
+        if body.len() > max_size {
+            bail!(
+                "response of {} bytes exceeds the {} byte limit",
+                body.len(),
+                max_size
+            );
+        }
+        let response: JsonResponse =
+            serde_json::from_slice(&body).context("invalid JSON DoH response")?;
+
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_response_code(ResponseCode::from_low(response.status as u8))
+            .set_truncated(response.truncated)
+            .set_recursion_desired(response.recursion_desired)
+            .set_recursion_available(response.recursion_available)
+            .set_authentic_data(response.authentic_data)
+            .set_checking_disabled(response.checking_disabled)
+            .add_query(Query::query(name.clone(), query_type));
+        for record in &response.answer {
+            message.add_answer(record.to_record()?);
+        }
+        for record in &response.authority {
+            message.add_name_server(record.to_record()?);
+        }
+        for record in &response.additional {
+            message.add_additional(record.to_record()?);
+        }
+        Ok(message)
+    }
+}
+
+impl JsonRecord {
+    fn to_record(&self) -> Result<Record> {
+        let record_type = RecordType::from(self.record_type);
+        let name = Name::from_str(&self.name)
+            .with_context(|| format!("invalid record name {:?}", self.name))?;
+        let rdata = RData::try_from_str(record_type, &self.data)
+            .with_context(|| format!("invalid {} record data {:?}", record_type, self.data))?;
+        Ok(Record::from_rdata(name, self.ttl, rdata))
+    }
+}
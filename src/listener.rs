@@ -0,0 +1,115 @@
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{future::Future, io, net::SocketAddr, time::Duration};
+use tokio::net::{TcpListener, UdpSocket};
+
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    pub reuseport: bool,
+    pub udp_rcvbuf: Option<usize>,
+    pub udp_sndbuf: Option<usize>,
+    /// Set IPV6_V6ONLY on IPv6 sockets
+    pub ipv6_only: bool,
+}
+
+/// How binding an address that is still in use is retried, e.g. while a
+/// previous instance releases it during a restart
+#[derive(Debug, Clone, Copy)]
+pub struct BindRetry {
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each further one
+    pub delay: Duration,
+}
+
+/// Runs `bind` until it succeeds, retrying while the address is in use. Other
+/// errors, such as a missing permission, are returned at once.
+pub async fn with_retry<T, F, Fut>(retry: BindRetry, what: &str, mut bind: F) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut delay = retry.delay;
+    let mut attempt = 0;
+    loop {
+        match bind().await {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && attempt < retry.retries => {
+                attempt += 1;
+                tracing::warn!(
+                    "{} is in use, retrying in {:?} ({}/{})",
+                    what,
+                    delay,
+                    attempt,
+                    retry.retries
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub async fn bind_udp(addr: &str, options: &SocketOptions) -> io::Result<UdpSocket> {
+    let addr = resolve_addr(addr).await?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    apply_socket_options(&socket, options)?;
+    if let Some(size) = options.udp_rcvbuf {
+        socket.set_recv_buffer_size(size)?;
+        tracing::info!(
+            "UDP receive buffer on {}: requested {}, effective {}",
+            addr,
+            size,
+            socket.recv_buffer_size()?
+        );
+    }
+    if let Some(size) = options.udp_sndbuf {
+        socket.set_send_buffer_size(size)?;
+        tracing::info!(
+            "UDP send buffer on {}: requested {}, effective {}",
+            addr,
+            size,
+            socket.send_buffer_size()?
+        );
+    }
+    socket.bind(&SockAddr::from(addr))?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+pub async fn bind_tcp(addr: &str, options: &SocketOptions) -> io::Result<TcpListener> {
+    let addr = resolve_addr(addr).await?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Match the behavior of `TcpListener::bind`, which enables SO_REUSEADDR on Unix
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    apply_socket_options(&socket, options)?;
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+async fn resolve_addr(addr: &str) -> io::Result<SocketAddr> {
+    tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("could not resolve listen address {addr}"),
+        )
+    })
+}
+
+fn apply_socket_options(socket: &Socket, options: &SocketOptions) -> io::Result<()> {
+    if options.ipv6_only && socket.domain()? == Domain::IPV6 {
+        socket.set_only_v6(true)?;
+    }
+    if options.reuseport {
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+
+        #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+        {
+            let _ = socket;
+            tracing::warn!("SO_REUSEPORT is not supported on this platform, ignoring");
+        }
+    }
+    Ok(())
+}
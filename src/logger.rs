@@ -1,14 +1,32 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
 use time::macros::format_description;
 use tracing_subscriber::{
-    filter::filter_fn, layer::SubscriberExt, util::SubscriberInitExt, Layer as _,
+    Layer as _, filter::filter_fn, layer::SubscriberExt, util::SubscriberInitExt,
 };
 
+use crate::config::{LogConfig, LogRotation};
+
+/// Target of the per-query access log events, written only to the `access_log` file
+pub const ACCESS_TARGET: &str = "access";
+
 pub fn init_logger(
     log_level_filter: &str,
     error_log: Option<String>,
+    log_config: &LogConfig,
 ) -> Vec<tracing_appender::non_blocking::WorkerGuard> {
-    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+    let mut env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| log_level_filter.into());
+    if log_config.access_log.is_some() {
+        env_filter = env_filter.add_directive(
+            format!("{ACCESS_TARGET}=info")
+                .parse()
+                .expect("valid access log directive"),
+        );
+    }
 
     // Create custom time format: yyyy-MM-dd HH:mm:ss.SSS
     let format =
@@ -25,10 +43,7 @@ pub fn init_logger(
     let guard;
 
     if let Some(error_log) = error_log {
-        let base_path = std::path::Path::new(&error_log).parent().unwrap();
-        let filename = std::path::Path::new(&error_log).file_name().unwrap();
-        let file_appender = tracing_appender::rolling::daily(base_path, filename);
-        (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+        (non_blocking, guard) = file_writer(&error_log, log_config);
         let (stdout_non_blocking, guard_stdout) = tracing_appender::non_blocking(std::io::stdout());
         guards.push(guard_stdout);
         stdout = Some(stdout_non_blocking)
@@ -37,13 +52,26 @@ pub fn init_logger(
     }
     guards.push(guard);
 
+    let access_layer = log_config.access_log.as_ref().map(|access_log| {
+        let (access_non_blocking, access_guard) = file_writer(access_log, log_config);
+        guards.push(access_guard);
+        tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_timer(timer.clone())
+            .with_level(false)
+            .with_target(false)
+            .with_writer(access_non_blocking)
+            .with_filter(filter_fn(|metadata| metadata.target() == ACCESS_TARGET))
+    });
+
     tracing_subscriber::registry()
         .with(env_filter)
         .with(
             tracing_subscriber::fmt::layer()
                 .with_ansi(stdout.is_none())
                 .with_timer(timer.clone())
-                .with_writer(non_blocking.clone()),
+                .with_writer(non_blocking.clone())
+                .with_filter(filter_fn(|metadata| metadata.target() != ACCESS_TARGET)),
         )
         .with(
             tracing_subscriber::fmt::layer()
@@ -55,7 +83,96 @@ pub fn init_logger(
                             || metadata.target().ends_with(":stderr"))
                 })),
         )
+        .with(access_layer)
         .init();
 
     guards
 }
+
+/// A non-blocking writer for a log file, rotated as configured
+fn file_writer(
+    path: &str,
+    log_config: &LogConfig,
+) -> (
+    tracing_appender::non_blocking::NonBlocking,
+    tracing_appender::non_blocking::WorkerGuard,
+) {
+    if log_config.rotation == LogRotation::Size {
+        let file_appender =
+            SizeRotatingWriter::new(path, log_config.max_size, log_config.max_files)
+                .expect("failed to open log file");
+        tracing_appender::non_blocking(file_appender)
+    } else {
+        let base_path = std::path::Path::new(path).parent().unwrap();
+        let filename = std::path::Path::new(path).file_name().unwrap();
+        let file_appender = tracing_appender::rolling::daily(base_path, filename);
+        tracing_appender::non_blocking(file_appender)
+    }
+}
+
+/// Log file writer that rotates by size, shifting `file` to `file.1`,
+/// `file.1` to `file.2` and so on, keeping at most `max_files` rotated files
+struct SizeRotatingWriter {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl SizeRotatingWriter {
+    fn new(path: impl AsRef<Path>, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                match fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -8,51 +8,173 @@ use anyhow::Result;
 use clap::Parser;
 use hickory_server::ServerFuture;
 use std::time::Duration;
-use tokio::{
-    net::{TcpListener, UdpSocket},
-    signal,
-};
+use tokio::signal;
 
+mod cache;
+mod capture;
 mod client;
 mod config;
 mod handler;
+mod json_doh;
+mod listener;
 mod logger;
+mod observer;
+mod reload;
+mod retransmit;
+mod route;
+mod runtime;
+mod selftest;
+mod servfail;
+mod stats;
+mod tls_info;
+#[cfg(unix)]
+mod unix;
+mod version;
+mod zone;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// DNS server listening host
-    #[arg(short, long, default_value = "[::]")]
-    host: String,
+    /// DNS server listening host; a comma-separated list binds each host separately
+    #[arg(long, default_value = "[::]", value_delimiter = ',')]
+    host: Vec<String>,
 
-    /// DNS server listening port
-    #[arg(short, long, default_value_t = 5653)]
-    port: u16,
+    /// DNS server listening port; a comma-separated list listens on each port
+    #[arg(short, long, default_value = "5653", value_delimiter = ',')]
+    port: Vec<u16>,
 
     #[arg(long, help = "Log filepath")]
     log: Option<String>,
 
-    /// Configuration file path
+    /// Only log warnings and errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log more details: -v for debug, -vv for trace (RUST_LOG takes precedence)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Configuration file path, `-` to read from stdin, or an HTTP(S) URL
     #[arg(short, long, default_value = "race-dns-proxy.toml")]
     config: String,
+
+    /// Enable SO_REUSEPORT so multiple processes can share the listening port
+    #[arg(long)]
+    reuseport: bool,
+
+    /// UDP socket receive buffer size in bytes (SO_RCVBUF)
+    #[arg(long)]
+    udp_rcvbuf: Option<usize>,
+
+    /// UDP socket send buffer size in bytes (SO_SNDBUF)
+    #[arg(long)]
+    udp_sndbuf: Option<usize>,
+
+    /// Retries of a listening address that is still in use, e.g. during a restart
+    #[arg(long, default_value_t = 3)]
+    bind_retries: u32,
+
+    /// Delay in milliseconds before the first bind retry, doubled for each further one
+    #[arg(long, default_value_t = 250)]
+    bind_retry_delay_ms: u64,
+
+    /// Seconds to wait for a graceful shutdown before forcing exit
+    #[arg(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+
+    /// Write sampled queries and responses to this pcap file
+    #[arg(long)]
+    capture: Option<String>,
+
+    /// Fraction of queries to capture, from 0.0 to 1.0
+    #[arg(long, default_value_t = 1.0)]
+    capture_sample: f64,
+
+    /// Print version, enabled features and linked hickory version, then exit
+    #[arg(long)]
+    version_full: bool,
+
+    /// Query the running server on the first --host and --port and exit 0 if it answers, 1 otherwise
+    #[arg(long)]
+    selftest: bool,
+
+    /// Name queried by --selftest
+    #[arg(long, default_value = "example.com")]
+    selftest_name: String,
+
+    /// Print which providers a query for this name is raced across and why, then exit
+    #[arg(long, value_name = "NAME")]
+    route: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    // Also rejects NaN, which parses as a float
+    if !(0.0..=1.0).contains(&args.capture_sample) {
+        anyhow::bail!("--capture-sample must be between 0.0 and 1.0");
+    }
+
+    if args.version_full {
+        println!("{}", version::full());
+        return Ok(());
+    }
 
-    let _guard = logger::init_logger("race_dns_proxy=info,info", args.log);
+    if args.selftest {
+        match selftest::run(&args.host[0], args.port[0], &args.selftest_name).await {
+            Ok(()) => {
+                println!("OK");
+                return Ok(());
+            }
+            Err(err) => {
+                eprintln!("Self-test failed: {err:#}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Load configuration file; errors are logged once the logger is set up,
+    // which itself needs the configured log rotation
+    let config = config::Config::load(&args.config).await;
+    let log_config = config
+        .as_ref()
+        .map(|config| config.log.clone())
+        .unwrap_or_default();
+    let log_filter = match (args.quiet, args.verbose) {
+        (true, _) => "race_dns_proxy=warn,warn",
+        (false, 0) => "race_dns_proxy=info,info",
+        (false, 1) => "race_dns_proxy=debug,info",
+        (false, _) => "race_dns_proxy=trace,info",
+    };
+    let log_guards = logger::init_logger(log_filter, args.log, &log_config);
 
-    // Load configuration file
-    let config = match config::Config::load(&args.config) {
+    let config = match config {
         Ok(config) => config,
         Err(err) => {
             tracing::error!("Failed to load configuration file: {}", err);
             return Err(err);
         }
     };
+    config.log_env_providers();
+
+    if let Some(name) = &args.route {
+        println!("{}", route::explain(&config, name)?);
+        return Ok(());
+    }
+
+    if let Some(path) = &args.capture {
+        if let Err(err) = capture::init(path, args.capture_sample) {
+            tracing::error!("Failed to create capture file {}: {}", path, err);
+            return Err(err.into());
+        }
+        tracing::info!(
+            "Capturing {}% of queries to {}",
+            args.capture_sample * 100.0,
+            path
+        );
+    }
 
-    let handler = match handler::RaceHandler::new(&config).await {
+    let handler = match handler::RaceHandler::new(&config, None).await {
         Ok(handler) => handler,
         Err(err) => {
             tracing::error!("Failed to initialize race handler: {}", err);
@@ -60,35 +182,110 @@ async fn main() -> Result<()> {
         }
     };
 
-    let pkg_name = env!("CARGO_PKG_NAME");
-    let pkg_version = env!("CARGO_PKG_VERSION");
-    tracing::info!("Starting {} v{}", pkg_name, pkg_version);
+    if config.startup.wait_for_first_connection {
+        let timeout = Duration::from_millis(config.startup.wait_timeout_ms);
+        tracing::info!(
+            "Waiting up to {:?} for the first provider connection",
+            timeout
+        );
+        if !handler.wait_for_connection(timeout).await {
+            tracing::warn!(
+                "No provider connected within {:?}, starting anyway",
+                timeout
+            );
+        }
+    }
+    let handler = handler::SharedHandler::new(handler);
 
-    let mut server = ServerFuture::new(handler);
+    tracing::info!("Starting {}", version::full());
 
-    // Listen on UDP port
-    let addr = format!("{}:{}", args.host, args.port);
-    let socket = match UdpSocket::bind(&addr).await {
-        Ok(socket) => socket,
-        Err(err) => {
-            tracing::error!("Failed to bind UDP socket on {}: {}", addr, err);
-            return Err(err.into());
+    if config.reload.watch {
+        if !config::is_file_source(&args.config) {
+            tracing::warn!("Configuration watch is only supported for local files, ignoring");
+        } else if let Err(err) = reload::watch(
+            args.config.clone(),
+            handler.clone(),
+            Duration::from_millis(config.reload.debounce_ms),
+        ) {
+            tracing::error!("Failed to watch configuration file: {}", err);
+            return Err(err);
         }
+    }
+
+    let mut server = ServerFuture::new(handler.clone());
+    let socket_options = listener::SocketOptions {
+        reuseport: args.reuseport,
+        udp_rcvbuf: args.udp_rcvbuf,
+        udp_sndbuf: args.udp_sndbuf,
+        // With several hosts, IPv6 sockets must not also claim IPv4 so that
+        // `0.0.0.0` and `[::]` can be bound side by side
+        ipv6_only: args.host.len() > 1,
+    };
+    let bind_retry = listener::BindRetry {
+        retries: args.bind_retries,
+        delay: Duration::from_millis(args.bind_retry_delay_ms),
     };
-    tracing::info!("DNS proxy server listening on {}/UDP", addr);
-    server.register_socket(socket);
 
-    // Listen on TCP port
-    let addr = format!("{}:{}", args.host, args.port);
-    let listener = match TcpListener::bind(&addr).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            tracing::error!("Failed to bind TCP listener on {}: {}", addr, err);
-            return Err(err.into());
+    // A port that fails to bind is skipped as long as some other address binds
+    let mut bound = 0;
+    for host in &args.host {
+        for port in &args.port {
+            let addr = format!("{}:{}", host, port);
+
+            // Listen on UDP port
+            match listener::with_retry(bind_retry, &format!("{addr}/UDP"), || {
+                listener::bind_udp(&addr, &socket_options)
+            })
+            .await
+            {
+                Ok(socket) => {
+                    tracing::info!("DNS proxy server listening on {}/UDP", addr);
+                    server.register_socket(socket);
+                    bound += 1;
+                }
+                Err(err) => tracing::error!("Failed to bind UDP socket on {}: {}", addr, err),
+            }
+
+            // Listen on TCP port
+            match listener::with_retry(bind_retry, &format!("{addr}/TCP"), || {
+                listener::bind_tcp(&addr, &socket_options)
+            })
+            .await
+            {
+                Ok(listener) => {
+                    tracing::info!("DNS proxy server listening on {}/TCP", addr);
+                    server.register_listener(listener, Duration::from_secs(10));
+                    bound += 1;
+                }
+                Err(err) => tracing::error!("Failed to bind TCP listener on {}: {}", addr, err),
+            }
         }
-    };
-    tracing::info!("DNS proxy server listening on {}/TCP", addr);
-    server.register_listener(listener, Duration::from_secs(10));
+    }
+    if bound == 0 {
+        tracing::error!("Failed to bind any listening address");
+        anyhow::bail!("no listening address could be bound");
+    }
+
+    if let Some(path) = &config.listen.unix {
+        #[cfg(unix)]
+        {
+            let listener = match unix::bind(path) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    tracing::error!("Failed to bind Unix socket {}: {}", path, err);
+                    return Err(err.into());
+                }
+            };
+            tracing::info!("DNS proxy server listening on {}", path);
+            unix::serve(listener, handler);
+        }
+
+        #[cfg(not(unix))]
+        tracing::warn!(
+            "Unix sockets are not supported on this platform, ignoring {}",
+            path
+        );
+    }
 
     let ctrl_c = async {
         signal::ctrl_c()
@@ -107,17 +304,43 @@ async fn main() -> Result<()> {
     #[cfg(not(unix))]
     let terminate = std::future::pending::<()>();
 
+    if let Some(interval) = config.stats.log_interval_secs {
+        stats::log_periodically(Duration::from_secs(interval));
+    }
+
     tokio::select! {
         _ = ctrl_c => {},
         _ = terminate => {},
     }
 
-    match server.shutdown_gracefully().await {
-        Ok(_) => tracing::info!("Server shutdown completed"),
-        Err(err) => {
+    let latencies = stats::STATS.latency_averages();
+    if !latencies.is_empty() {
+        tracing::info!(
+            "Average provider latencies: {}",
+            latencies
+                .iter()
+                .map(|(provider, latency)| format!("{provider} {latency:?}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    let shutdown_timeout = Duration::from_secs(args.shutdown_timeout);
+    match tokio::time::timeout(shutdown_timeout, server.shutdown_gracefully()).await {
+        Ok(Ok(_)) => tracing::info!("Server shutdown completed"),
+        Ok(Err(err)) => {
             tracing::error!("Error during server shutdown: {}", err);
             return Err(err.into());
         }
+        Err(_) => {
+            tracing::warn!(
+                "Graceful shutdown did not complete within {:?}, forcing exit",
+                shutdown_timeout
+            );
+            // Flush pending log lines before exiting without unwinding the runtime
+            drop(log_guards);
+            std::process::exit(1);
+        }
     };
 
     Ok(())
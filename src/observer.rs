@@ -0,0 +1,79 @@
+use hickory_proto::{op::ResponseCode, rr::RecordType};
+use std::{fmt::Write, time::Duration};
+
+use crate::{client::Transport, config::ObserverKind};
+
+/// How one provider fared in a race
+#[derive(Debug, Clone)]
+pub struct ProviderTiming {
+    pub provider: String,
+    pub transport: Transport,
+    pub elapsed: Duration,
+    /// `None` if the query to the provider failed
+    pub response_code: Option<ResponseCode>,
+}
+
+/// The outcome of a race, handed to a [`QueryObserver`]
+#[derive(Debug, Clone)]
+pub struct RaceEvent {
+    pub name: String,
+    pub query_type: RecordType,
+    /// Provider whose answer was sent, as a winner or as a fallback
+    pub winner: Option<String>,
+    /// Response code sent to the client; `None` if no response could be sent
+    pub response_code: Option<ResponseCode>,
+    /// Results that arrived before the race ended, in arrival order
+    pub timings: Vec<ProviderTiming>,
+}
+
+/// Receives the outcome of every race, e.g. to push events to an external system.
+/// It is called from a separate task after the response was sent, so a slow
+/// observer does not delay answers.
+#[async_trait::async_trait]
+pub trait QueryObserver: Send + Sync {
+    async fn on_race(&self, event: RaceEvent);
+}
+
+/// Logs every event as one line
+pub struct LoggingObserver;
+
+#[async_trait::async_trait]
+impl QueryObserver for LoggingObserver {
+    async fn on_race(&self, event: RaceEvent) {
+        let mut timings = String::new();
+        for timing in &event.timings {
+            if !timings.is_empty() {
+                timings.push_str(", ");
+            }
+            let _ = match timing.response_code {
+                Some(code) => write!(
+                    timings,
+                    "{}/{} {} {:?}",
+                    timing.provider, timing.transport, code, timing.elapsed
+                ),
+                None => write!(
+                    timings,
+                    "{}/{} failed {:?}",
+                    timing.provider, timing.transport, timing.elapsed
+                ),
+            };
+        }
+        tracing::info!(
+            "race {}({}) {} winner {} | {}",
+            event.name,
+            event.query_type,
+            event.response_code.map_or("-", |code| code.to_str()),
+            event.winner.as_deref().unwrap_or("-"),
+            timings
+        );
+    }
+}
+
+/// Creates the observer selected in the configuration; `None` if there is none,
+/// in which case no events are built at all
+pub fn create(kind: ObserverKind) -> Option<Box<dyn QueryObserver>> {
+    match kind {
+        ObserverKind::None => None,
+        ObserverKind::Log => Some(Box::new(LoggingObserver)),
+    }
+}
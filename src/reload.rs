@@ -0,0 +1,64 @@
+use anyhow::Result;
+use notify::{RecursiveMode, Watcher};
+use std::{path::Path, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::{
+    config::Config,
+    handler::{RaceHandler, SharedHandler},
+};
+
+/// Reloads the configuration and swaps in a freshly built handler, which keeps the
+/// connections and cache entries of unchanged providers. On error the running
+/// handler is left untouched.
+pub async fn reload(source: &str, handler: &SharedHandler) -> Result<()> {
+    let config = Config::load(source).await?;
+    config.log_env_providers();
+    let race_handler = RaceHandler::new(&config, Some(&handler.current())).await?;
+    handler.replace(race_handler).await;
+    Ok(())
+}
+
+/// Watches the configuration file and reloads it after changes settle for `debounce`
+pub fn watch(source: String, handler: SharedHandler, debounce: Duration) -> Result<()> {
+    let path = Path::new(&source).canonicalize()?;
+    // Watch the parent directory so editors that replace the file are picked up
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Configuration file has no parent directory"))?
+        .to_path_buf();
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let watched_path = path.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            // Ignore access events, which our own reads of the file generate
+            Ok(event) if !event.kind.is_access() && event.paths.contains(&watched_path) => {
+                let _ = tx.send(());
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("Configuration watch error: {}", e),
+        })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    tracing::info!("Watching configuration file {}", path.display());
+
+    tokio::spawn(async move {
+        // Keep the watcher alive for as long as the task runs
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            // Debounce: wait until no further change arrives within the quiet period
+            while let Ok(Some(())) = tokio::time::timeout(debounce, rx.recv()).await {}
+
+            tracing::info!("Configuration file changed, reloading");
+            match reload(&source, &handler).await {
+                Ok(()) => tracing::info!("Configuration reloaded"),
+                Err(e) => tracing::error!(
+                    "Failed to reload configuration, keeping the running one: {:#}",
+                    e
+                ),
+            }
+        }
+    });
+
+    Ok(())
+}
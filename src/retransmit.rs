@@ -0,0 +1,63 @@
+use hickory_proto::rr::LowerName;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A query as identified by the client that sent it
+type Key = (SocketAddr, u16, LowerName);
+
+/// Tracks queries that are still being answered so that a client retransmitting
+/// one (same source, ID and name) does not start a second race
+pub struct RetransmitFilter {
+    in_flight: Mutex<HashMap<Key, Instant>>,
+    window: Duration,
+}
+
+/// Marks a query as in flight until dropped
+pub struct InFlight<'a> {
+    filter: &'a RetransmitFilter,
+    key: Key,
+    started: Instant,
+}
+
+impl RetransmitFilter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+            window,
+        }
+    }
+
+    /// Registers a query, or returns `None` if the same query arrived less than
+    /// the window ago and is still being answered
+    pub fn begin(&self, src: SocketAddr, id: u16, name: LowerName) -> Option<InFlight<'_>> {
+        let key = (src, id, name);
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(&key)
+            .is_some_and(|started| started.elapsed() < self.window)
+        {
+            return None;
+        }
+        let started = Instant::now();
+        in_flight.insert(key.clone(), started);
+        Some(InFlight {
+            filter: self,
+            key,
+            started,
+        })
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.filter.in_flight.lock().unwrap();
+        // A query that outlived the window may have been replaced by a newer copy
+        if in_flight.get(&self.key) == Some(&self.started) {
+            in_flight.remove(&self.key);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::Result;
+use hickory_proto::rr::DNSClass;
+use std::fmt::Write;
+
+use crate::{
+    config::{Config, ProviderInfo},
+    handler::{RuleMatch, domain_rule_match, select_by_domain},
+};
+
+/// Describes which providers a class IN query for `name` is raced across and
+/// why, using the same selection as query handling. Local zones, local records,
+/// blocklists and the cache, which may answer before any provider, are not considered.
+pub fn explain(config: &Config, name: &str) -> Result<String> {
+    let mut providers = config.get_providers()?;
+    providers.sort_by(|a, b| a.name.cmp(&b.name));
+    let groups = config.get_groups();
+
+    let serves_in = |provider: &ProviderInfo| {
+        provider.query_classes.is_empty() || provider.query_classes.contains(&DNSClass::IN)
+    };
+    let candidates: Vec<_> = providers.iter().filter(|p| serves_in(p)).collect();
+    let conflict = config.routing.conflict;
+    let (selected, specific) = select_by_domain(candidates, name, conflict, |p| &p.domain_rules);
+
+    let mut out = String::new();
+    writeln!(out, "Route for {name} (class IN):")?;
+    if specific {
+        writeln!(
+            out,
+            "  matched by domain rules, catch-all providers are not used"
+        )?;
+    } else {
+        writeln!(
+            out,
+            "  no domain rules match, falling back to catch-all providers"
+        )?;
+    }
+
+    for provider in &providers {
+        let is_selected = selected.iter().any(|p| p.name == provider.name);
+        let reason = if !serves_in(provider) {
+            "does not serve class IN".to_string()
+        } else if provider.domain_rules.0.is_empty() {
+            if is_selected {
+                "catch-all".to_string()
+            } else {
+                "catch-all, not used".to_string()
+            }
+        } else {
+            // The provider's rules combine those of its groups; report the group
+            // whose pattern decided
+            let group_names = config
+                .providers
+                .get(&provider.name)
+                .map_or(&[][..], |p| &p.domain_groups[..]);
+            let group_of = |pattern: &str, excluded: bool| {
+                group_names
+                    .iter()
+                    .find(|group_name| {
+                        groups.iter().any(|(name, (includes, excludes), _)| {
+                            name == *group_name
+                                && if excluded { excludes } else { includes }
+                                    .iter()
+                                    .any(|p| p == pattern)
+                        })
+                    })
+                    .map_or("-", String::as_str)
+            };
+            match domain_rule_match(name, &provider.domain_rules, conflict) {
+                RuleMatch::Excluded(pattern) => format!(
+                    "excluded by group {}, !{}",
+                    group_of(pattern, true),
+                    pattern
+                ),
+                RuleMatch::Included(pattern) => {
+                    format!("group {}, {}", group_of(pattern, false), pattern)
+                }
+                RuleMatch::All | RuleMatch::None => "no domain rule matches".to_string(),
+            }
+        };
+
+        let mut flags = format!("tier {}", provider.tier);
+        if provider.monitor {
+            flags.push_str(", monitor");
+        }
+        writeln!(
+            out,
+            "  {} {} ({}): {}",
+            if is_selected { "✔" } else { "✘" },
+            provider.name,
+            flags,
+            reason
+        )?;
+    }
+    Ok(out.trim_end().to_string())
+}
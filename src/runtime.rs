@@ -0,0 +1,213 @@
+use hickory_proto::runtime::{
+    RuntimeProvider, TokioHandle, TokioRuntimeProvider, TokioTime, iocompat::AsyncIoTokioAsStd,
+};
+use std::{future::Future, io, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+/// Highest DSCP value; the code point is 6 bits wide (RFC 2474)
+pub const MAX_DSCP: u8 = 63;
+
+/// Same as hickory's connect timeout for TCP connections
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tokio runtime whose upstream TCP connections are marked with a DSCP value
+/// (IP_TOS on IPv4, IPV6_TCLASS on IPv6) and/or opened inside a network namespace
+#[derive(Clone)]
+pub struct UpstreamRuntimeProvider {
+    inner: TokioRuntimeProvider,
+    dscp: Option<u8>,
+    netns: Option<Arc<str>>,
+}
+
+impl UpstreamRuntimeProvider {
+    /// Returns `None` if neither option is set, in which case plain tokio sockets suffice
+    pub fn new(dscp: Option<u8>, netns: Option<&str>) -> Option<Self> {
+        (dscp.is_some() || netns.is_some()).then(|| Self {
+            inner: TokioRuntimeProvider::new(),
+            dscp,
+            netns: netns.map(Arc::from),
+        })
+    }
+
+    /// Connects a plain TCP stream with the configured socket options
+    pub async fn connect(
+        &self,
+        server_addr: SocketAddr,
+        bind_addr: Option<SocketAddr>,
+        wait_for: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        let v6 = server_addr.is_ipv6();
+        let socket = match &self.netns {
+            Some(netns) => netns::tcp_socket(netns, v6)?,
+            None if v6 => TcpSocket::new_v6()?,
+            None => TcpSocket::new_v4()?,
+        };
+        if let Some(bind_addr) = bind_addr {
+            socket.bind(bind_addr)?;
+        }
+        if let Some(dscp) = self.dscp
+            && let Err(e) = set_dscp(&socket, server_addr, dscp)
+        {
+            tracing::warn!("Unable to set DSCP {} for {}: {}", dscp, server_addr, e);
+        }
+        socket.set_nodelay(true)?;
+
+        let wait_for = wait_for.unwrap_or(CONNECT_TIMEOUT);
+        match tokio::time::timeout(wait_for, socket.connect(server_addr)).await {
+            Ok(stream) => stream,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("connection to {server_addr} timed out after {wait_for:?}"),
+            )),
+        }
+    }
+}
+
+impl RuntimeProvider for UpstreamRuntimeProvider {
+    type Handle = TokioHandle;
+    type Timer = TokioTime;
+    type Udp = UdpSocket;
+    type Tcp = AsyncIoTokioAsStd<TcpStream>;
+
+    fn create_handle(&self) -> Self::Handle {
+        self.inner.create_handle()
+    }
+
+    fn connect_tcp(
+        &self,
+        server_addr: SocketAddr,
+        bind_addr: Option<SocketAddr>,
+        wait_for: Option<Duration>,
+    ) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Tcp>>>> {
+        let provider = self.clone();
+        Box::pin(async move {
+            provider
+                .connect(server_addr, bind_addr, wait_for)
+                .await
+                .map(AsyncIoTokioAsStd)
+        })
+    }
+
+    /// DoH only connects over TCP, so UDP sockets are left as they are
+    fn bind_udp(
+        &self,
+        local_addr: SocketAddr,
+        server_addr: SocketAddr,
+    ) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Udp>>>> {
+        self.inner.bind_udp(local_addr, server_addr)
+    }
+}
+
+/// The DSCP occupies the upper six bits of the TOS / traffic class byte
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_dscp(socket: &TcpSocket, server_addr: SocketAddr, dscp: u8) -> io::Result<()> {
+    let socket = socket2::SockRef::from(socket);
+    let tos = u32::from(dscp) << 2;
+    match server_addr {
+        SocketAddr::V4(_) => socket.set_tos_v4(tos),
+        SocketAddr::V6(_) => socket.set_tclass_v6(tos),
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_dscp(_socket: &TcpSocket, _server_addr: SocketAddr, _dscp: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DSCP marking is not supported on this platform",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod netns {
+    use std::{
+        fs::File,
+        io,
+        os::fd::{AsRawFd, FromRawFd, IntoRawFd},
+        path::PathBuf,
+    };
+    use tokio::net::TcpSocket;
+
+    /// Where `ip netns add` creates named namespaces
+    const NETNS_RUN_DIR: &str = "/run/netns";
+
+    /// A namespace given by name lives in [`NETNS_RUN_DIR`]; anything containing a
+    /// slash is taken as a path, e.g. `/proc/<pid>/ns/net`
+    fn path(netns: &str) -> PathBuf {
+        if netns.contains('/') {
+            PathBuf::from(netns)
+        } else {
+            PathBuf::from(NETNS_RUN_DIR).join(netns)
+        }
+    }
+
+    fn setns(file: &File) -> io::Result<()> {
+        // SAFETY: the descriptor is open for the duration of the call
+        if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Creates a TCP socket inside the network namespace. A socket stays in the
+    /// namespace it was created in, so the calling thread only enters it for the
+    /// `socket()` call and switches back before returning. Requires CAP_SYS_ADMIN.
+    pub fn tcp_socket(netns: &str, v6: bool) -> io::Result<TcpSocket> {
+        let path = path(netns);
+        let target = File::open(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("network namespace {}: {e}", path.display()),
+            )
+        })?;
+        let original = File::open("/proc/thread-self/ns/net")?;
+        setns(&target).map_err(|e| {
+            io::Error::new(e.kind(), format!("entering network namespace {netns}: {e}"))
+        })?;
+        let socket = socket2::Socket::new(
+            if v6 {
+                socket2::Domain::IPV6
+            } else {
+                socket2::Domain::IPV4
+            },
+            socket2::Type::STREAM,
+            None,
+        );
+        if let Err(e) = setns(&original) {
+            // The worker thread would keep opening every socket in the wrong namespace
+            tracing::error!("Failed to leave network namespace {}: {}", netns, e);
+            std::process::abort();
+        }
+        let socket = socket?;
+        socket.set_nonblocking(true)?;
+        // SAFETY: the descriptor is a freshly created, owned TCP socket
+        Ok(unsafe { TcpSocket::from_raw_fd(socket.into_raw_fd()) })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod netns {
+    use std::io;
+    use tokio::net::TcpSocket;
+
+    pub fn tcp_socket(_netns: &str, _v6: bool) -> io::Result<TcpSocket> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "network namespaces are only supported on Linux",
+        ))
+    }
+}
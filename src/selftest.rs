@@ -0,0 +1,64 @@
+use anyhow::{Context, Result, bail};
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{Name, RecordType},
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+const SELFTEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Queries the running server over UDP and succeeds if it answers `name` with records
+pub async fn run(host: &str, port: u16, name: &str) -> Result<()> {
+    let addr = target_addr(host, port).await?;
+    let name = Name::from_str(name).with_context(|| format!("Invalid self-test name {name:?}"))?;
+
+    let mut query = Message::new();
+    query
+        .set_id(rand::random())
+        .set_recursion_desired(true)
+        .add_query(Query::query(name, RecordType::A));
+
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    socket.send(&query.to_vec()?).await?;
+
+    let mut buf = vec![0; 65535];
+    let len = tokio::time::timeout(SELFTEST_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .with_context(|| format!("No response from {addr} within {SELFTEST_TIMEOUT:?}"))??;
+    let response = Message::from_vec(&buf[..len])?;
+
+    if response.id() != query.id() {
+        bail!("Response ID mismatch from {addr}");
+    }
+    if response.response_code() != ResponseCode::NoError {
+        bail!("{} answered {}", addr, response.response_code());
+    }
+    if response.answers().is_empty() {
+        bail!("{} returned no answers", addr);
+    }
+    Ok(())
+}
+
+/// The listening address to query; unspecified hosts are reached via loopback
+async fn target_addr(host: &str, port: u16) -> Result<SocketAddr> {
+    let addr = tokio::net::lookup_host(format!("{host}:{port}"))
+        .await?
+        .next()
+        .with_context(|| format!("Could not resolve listen address {host}"))?;
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    Ok(SocketAddr::new(ip, port))
+}
@@ -0,0 +1,82 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::cache::CacheKey;
+
+/// Upper bound on remembered (provider, query) pairs; expired pairs are pruned first
+const MAX_ENTRIES: usize = 10_000;
+
+struct Entry {
+    servfails: u32,
+    last: Instant,
+}
+
+/// Short-lived memory of providers that keep answering SERVFAIL for a query.
+/// Once a provider reaches the threshold for a query, it is raced only after
+/// the other providers, until the memory of its last SERVFAIL expires.
+pub struct ServfailMemory {
+    entries: Mutex<HashMap<(String, CacheKey), Entry>>,
+    threshold: u32,
+    duration: Duration,
+}
+
+impl ServfailMemory {
+    pub fn new(threshold: u32, duration: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            threshold,
+            duration,
+        }
+    }
+
+    /// Whether `provider` answered SERVFAIL for the query often enough, recently
+    /// enough, to be deprioritized
+    pub fn is_deprioritized(&self, provider: &str, key: &CacheKey) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&(provider.to_string(), key.clone()))
+            .is_some_and(|entry| {
+                entry.servfails >= self.threshold && entry.last.elapsed() < self.duration
+            })
+    }
+
+    /// Records a SERVFAIL from `provider`; consecutive SERVFAILs count towards the threshold
+    pub fn record_servfail(&self, provider: &str, key: &CacheKey) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry_key = (provider.to_string(), key.clone());
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&entry_key) {
+            entries.retain(|_, entry| entry.last.elapsed() < self.duration);
+            if entries.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+
+        let entry = entries.entry(entry_key).or_insert(Entry {
+            servfails: 0,
+            last: Instant::now(),
+        });
+        if entry.last.elapsed() >= self.duration {
+            entry.servfails = 0;
+        }
+        entry.servfails += 1;
+        entry.last = Instant::now();
+        if entry.servfails == self.threshold {
+            tracing::warn!(
+                "Deprioritizing {} for {} {} after {} SERVFAILs",
+                provider,
+                key.name,
+                key.query_type,
+                entry.servfails
+            );
+        }
+    }
+
+    /// Forgets the SERVFAILs of `provider` once it answers the query
+    pub fn record_answer(&self, provider: &str, key: &CacheKey) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&(provider.to_string(), key.clone()));
+    }
+}
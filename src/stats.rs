@@ -0,0 +1,205 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Process-wide counters; they survive configuration reloads
+pub static STATS: Stats = Stats::new();
+
+pub struct Stats {
+    /// Queries received, not counting dropped retransmissions
+    queries: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    /// Races answered with a fallback response because no provider gave a good one
+    fallbacks: AtomicU64,
+    /// Latency averages keyed by provider name
+    latencies: Mutex<BTreeMap<String, Arc<LatencyAverage>>>,
+    /// Wins and errors keyed by provider name
+    providers: Mutex<BTreeMap<String, ProviderCounts>>,
+}
+
+/// How often a provider won a race or failed to answer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProviderCounts {
+    pub wins: u64,
+    pub errors: u64,
+}
+
+/// A point-in-time copy of the counters
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub queries: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub fallbacks: u64,
+    pub providers: BTreeMap<String, ProviderCounts>,
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            queries: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            fallbacks: AtomicU64::new(0),
+            latencies: Mutex::new(BTreeMap::new()),
+            providers: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record_query(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a race won by a provider
+    pub fn record_win(&self, provider: &str) {
+        self.provider_counts(provider, |counts| counts.wins += 1);
+    }
+
+    /// Counts a query to a provider that failed without a response
+    pub fn record_error(&self, provider: &str) {
+        self.provider_counts(provider, |counts| counts.errors += 1);
+    }
+
+    fn provider_counts(&self, provider: &str, update: impl FnOnce(&mut ProviderCounts)) {
+        let mut providers = self.providers.lock().unwrap();
+        match providers.get_mut(provider) {
+            Some(counts) => update(counts),
+            None => update(providers.entry(provider.to_string()).or_default()),
+        }
+    }
+
+    /// Counts a fallback response and returns the new total
+    pub fn record_fallback(&self) -> u64 {
+        self.fallbacks.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// The latency average of a provider, shared by every configuration that keeps its name
+    pub fn provider_latency(&self, provider: &str) -> Arc<LatencyAverage> {
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry(provider.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Forgets the latency averages and counts of providers for which `keep` returns false
+    pub fn retain_providers(&self, keep: impl Fn(&str) -> bool) {
+        self.latencies
+            .lock()
+            .unwrap()
+            .retain(|provider, _| keep(provider));
+        self.providers
+            .lock()
+            .unwrap()
+            .retain(|provider, _| keep(provider));
+    }
+
+    /// The latency average of every provider that has been measured, by name
+    pub fn latency_averages(&self) -> Vec<(String, Duration)> {
+        self.latencies
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(provider, latency)| Some((provider.clone(), latency.get()?)))
+            .collect()
+    }
+
+    /// A copy of the counters (latency averages excluded)
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            queries: self.queries.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            fallbacks: self.fallbacks.load(Ordering::Relaxed),
+            providers: self.providers.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl Snapshot {
+    /// One line summing up the counters, with the cache hit rate over all lookups
+    pub fn describe(&self) -> String {
+        let lookups = self.cache_hits + self.cache_misses;
+        let mut line = format!("{} queries, cache hits {}", self.queries, self.cache_hits);
+        if lookups > 0 {
+            line.push_str(&format!(
+                " ({:.1}%)",
+                self.cache_hits as f64 * 100.0 / lookups as f64
+            ));
+        }
+        line.push_str(&format!(", fallbacks {}", self.fallbacks));
+        let providers: Vec<_> = self
+            .providers
+            .iter()
+            .map(|(provider, counts)| {
+                format!("{provider}: {} wins, {} errors", counts.wins, counts.errors)
+            })
+            .collect();
+        if !providers.is_empty() {
+            line.push_str(" | ");
+            line.push_str(&providers.join("; "));
+        }
+        line
+    }
+}
+
+/// Logs the counters at INFO every `interval` until the process exits
+pub fn log_periodically(interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        // The first tick completes immediately
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            tracing::info!("Stats: {}", STATS.snapshot().describe());
+        }
+    });
+}
+
+/// Exponentially weighted moving average of a provider's response latency, in
+/// which each new measurement weighs 1/5
+#[derive(Default)]
+pub struct LatencyAverage {
+    /// In microseconds; 0 until the first measurement
+    micros: AtomicU64,
+}
+
+impl LatencyAverage {
+    pub fn record(&self, latency: Duration) {
+        let sample = u64::try_from(latency.as_micros())
+            .unwrap_or(u64::MAX)
+            .max(1);
+        let _ = self
+            .micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                Some(if average == 0 {
+                    sample
+                } else {
+                    average - average / 5 + sample / 5
+                })
+            });
+    }
+
+    pub fn get(&self) -> Option<Duration> {
+        match self.micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+}
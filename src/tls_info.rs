@@ -0,0 +1,75 @@
+use anyhow::{Context, Result};
+use rustls::{ClientConfig, pki_types::ServerName};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::runtime::UpstreamRuntimeProvider;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Logs the TLS parameters an upstream negotiates (version, cipher suite, ALPN) and
+/// its certificate's subject, issuer and expiry at debug level. The upstream
+/// connection does not expose its TLS session, so this performs a separate
+/// handshake with the same TLS configuration and socket options.
+pub async fn log_details(
+    addr: SocketAddr,
+    dns_name: String,
+    client_config: Arc<ClientConfig>,
+    runtime: Option<UpstreamRuntimeProvider>,
+) {
+    let details = probe(addr, &dns_name, client_config, runtime);
+    match tokio::time::timeout(PROBE_TIMEOUT, details).await {
+        Ok(Ok(details)) => tracing::debug!("TLS details of <{}>: {}", dns_name, details),
+        Ok(Err(e)) => tracing::debug!("Unable to read TLS details of <{}>: {:#}", dns_name, e),
+        Err(_) => tracing::debug!("Unable to read TLS details of <{}>: timed out", dns_name),
+    }
+}
+
+async fn probe(
+    addr: SocketAddr,
+    dns_name: &str,
+    client_config: Arc<ClientConfig>,
+    runtime: Option<UpstreamRuntimeProvider>,
+) -> Result<String> {
+    let server_name = ServerName::try_from(dns_name.to_string())?;
+    let stream = match runtime {
+        Some(runtime) => runtime.connect(addr, None, None).await?,
+        None => TcpStream::connect(addr).await?,
+    };
+    let stream = TlsConnector::from(client_config)
+        .connect(server_name, stream)
+        .await?;
+    let (_, connection) = stream.get_ref();
+
+    let mut details = format!(
+        "{:?}, {:?}, ALPN {}",
+        connection
+            .protocol_version()
+            .context("no protocol version")?,
+        connection
+            .negotiated_cipher_suite()
+            .context("no cipher suite")?
+            .suite(),
+        connection
+            .alpn_protocol()
+            .map_or("-".into(), String::from_utf8_lossy)
+    );
+    let certificate = connection
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .context("no peer certificate")?;
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate)
+        .map_err(|e| anyhow::anyhow!("invalid peer certificate: {e}"))?;
+    let validity = certificate.validity();
+    details.push_str(&format!(
+        ", subject {}, issuer {}, expires {}",
+        certificate.subject(),
+        certificate.issuer(),
+        validity.not_after
+    ));
+    if let Some(remaining) = validity.time_to_expiration() {
+        details.push_str(&format!(" (in {} days)", remaining.whole_days()));
+    }
+    Ok(details)
+}
@@ -0,0 +1,128 @@
+use hickory_proto::{
+    rr::Record,
+    serialize::binary::{BinDecodable, BinEncoder},
+    xfer::Protocol,
+};
+use hickory_server::{
+    authority::{MessageRequest, MessageResponse},
+    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream, unix::OwnedWriteHalf},
+    sync::Mutex,
+};
+
+/// Source address reported for requests received over a Unix socket
+const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Binds a Unix stream socket at `path`, replacing a stale socket file.
+/// Any other existing file is left alone and fails the bind.
+pub fn bind(path: &str) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{path} exists and is not a socket"),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+/// Serves DNS over a Unix stream socket using TCP framing (RFC 1035 4.2.2):
+/// every message is preceded by its length as a 16-bit big-endian integer
+pub fn serve<T: RequestHandler>(listener: UnixListener, handler: T) {
+    let handler = Arc::new(handler);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(stream, handler.clone()));
+                }
+                Err(e) => tracing::error!("Failed to accept Unix socket connection: {}", e),
+            }
+        }
+    });
+}
+
+async fn handle_connection<T: RequestHandler>(stream: UnixStream, handler: Arc<T>) {
+    let (mut reader, writer) = stream.into_split();
+    let response_handle = UnixResponseHandle {
+        writer: Arc::new(Mutex::new(writer)),
+    };
+
+    loop {
+        let len = match reader.read_u16().await {
+            Ok(len) => usize::from(len),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+            Err(e) => {
+                tracing::debug!("Unix socket read failed: {}", e);
+                return;
+            }
+        };
+        let mut buf = vec![0; len];
+        if let Err(e) = reader.read_exact(&mut buf).await {
+            tracing::debug!("Unix socket read failed: {}", e);
+            return;
+        }
+
+        let message = match MessageRequest::from_bytes(&buf) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::warn!("Dropping undecodable request on Unix socket: {}", e);
+                return;
+            }
+        };
+        let request = Request::new(message, UNIX_PEER_ADDR, Protocol::Tcp);
+        let handler = handler.clone();
+        let response_handle = response_handle.clone();
+        tokio::spawn(async move {
+            handler.handle_request(&request, response_handle).await;
+        });
+    }
+}
+
+/// Writes length-prefixed responses to a Unix socket connection
+#[derive(Clone)]
+struct UnixResponseHandle {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+}
+
+#[async_trait::async_trait]
+impl ResponseHandler for UnixResponseHandle {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut bytes = Vec::with_capacity(512);
+        let info = {
+            let mut encoder = BinEncoder::new(&mut bytes);
+            encoder.set_max_size(u16::MAX);
+            response
+                .destructive_emit(&mut encoder)
+                .map_err(io::Error::other)?
+        };
+
+        let mut writer = self.writer.lock().await;
+        writer.write_u16(bytes.len() as u16).await?;
+        writer.write_all(&bytes).await?;
+        Ok(info)
+    }
+}
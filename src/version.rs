@@ -0,0 +1,19 @@
+/// Cargo features and transports compiled into this build
+pub fn features() -> Vec<&'static str> {
+    let mut features = vec!["doh"];
+    if cfg!(feature = "mimalloc") {
+        features.push("mimalloc");
+    }
+    features
+}
+
+/// One-line build description: package version, features, and hickory version
+pub fn full() -> String {
+    format!(
+        "{} v{} (features: {}; hickory-dns {})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        features().join(", "),
+        env!("HICKORY_VERSION")
+    )
+}
@@ -0,0 +1,324 @@
+use anyhow::{Context, Result};
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{DNSClass, LowerName, Name, Record, RecordSet, RecordType, RrKey},
+    serialize::txt::Parser,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// Longest CNAME chain followed inside a zone
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// An RFC 1035 zone file answered authoritatively without racing upstream
+pub struct LocalZone {
+    origin: LowerName,
+    records: BTreeMap<RrKey, RecordSet>,
+    /// Every owner name in the zone and its ancestors up to the origin, so that
+    /// empty non-terminals get NODATA rather than NXDOMAIN (RFC 8020)
+    names: HashSet<LowerName>,
+    soa: Option<Record>,
+}
+
+impl LocalZone {
+    /// Loads and parses the zone file at `path` for the `origin` domain
+    pub async fn load(origin: &str, path: &str) -> Result<Self> {
+        let mut origin = Name::from_str(origin)
+            .with_context(|| format!("Invalid local zone name {origin:?}"))?;
+        origin.set_fqdn(true);
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read zone file {path}"))?;
+        let (origin, records) = Parser::new(contents, Some(PathBuf::from(path)), Some(origin))
+            .parse()
+            .with_context(|| format!("Failed to parse zone file {path}"))?;
+
+        let origin = LowerName::from(origin);
+        let soa = records
+            .get(&RrKey::new(origin.clone(), RecordType::SOA))
+            .and_then(|soa| soa.records_without_rrsigs().next().cloned());
+        if soa.is_none() {
+            tracing::warn!("Local zone {} has no SOA record", origin);
+        }
+        tracing::info!(
+            "Loaded local zone {} ({} record sets)",
+            origin,
+            records.len()
+        );
+
+        let mut names = HashSet::new();
+        for key in records.keys() {
+            let mut name = key.name().clone();
+            while origin.zone_of(&name) && names.insert(name.clone()) && name != origin {
+                name = name.base_name();
+            }
+        }
+
+        Ok(Self {
+            origin,
+            records,
+            names,
+            soa,
+        })
+    }
+
+    /// Answers a class IN query for a name inside this zone, or returns `None`
+    /// for other classes and names outside it
+    pub fn lookup(&self, query: &Query, recursion_desired: bool) -> Option<Message> {
+        let name = LowerName::from(query.name());
+        if query.query_class() != DNSClass::IN || !self.origin.zone_of(&name) {
+            return None;
+        }
+
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_authoritative(true)
+            .set_recursion_desired(recursion_desired)
+            .set_recursion_available(true)
+            .add_query(query.clone());
+
+        let mut name = name;
+        for _ in 0..MAX_CNAME_CHAIN {
+            if let Some(records) = self
+                .records
+                .get(&RrKey::new(name.clone(), query.query_type()))
+            {
+                message.add_answers(records.records_without_rrsigs().cloned());
+                return Some(message);
+            }
+            // Follow CNAMEs that stay inside the zone
+            let Some(cname) = self
+                .records
+                .get(&RrKey::new(name.clone(), RecordType::CNAME))
+            else {
+                break;
+            };
+            message.add_answers(cname.records_without_rrsigs().cloned());
+            let Some(target) = cname
+                .records_without_rrsigs()
+                .find_map(|record| record.data().as_cname().map(|cname| cname.0.clone()))
+            else {
+                return Some(message);
+            };
+            name = LowerName::from(target);
+            if !self.origin.zone_of(&name) {
+                return Some(message);
+            }
+        }
+
+        if message.answers().is_empty() {
+            if !self.names.contains(&name) {
+                message.set_response_code(ResponseCode::NXDomain);
+            }
+            if let Some(soa) = &self.soa {
+                message.add_name_server(soa.clone());
+            }
+        }
+        Some(message)
+    }
+
+    pub fn origin(&self) -> &LowerName {
+        &self.origin
+    }
+}
+
+/// Fixed records from the configuration, answered for their exact name and type.
+/// Names without a record of the queried type are left to the race.
+#[derive(Default)]
+pub struct LocalRecords {
+    records: HashMap<(LowerName, RecordType), Vec<Record>>,
+}
+
+impl LocalRecords {
+    pub fn new(records: Vec<Record>) -> Self {
+        let mut local = Self::default();
+        for record in records {
+            local
+                .records
+                .entry((LowerName::from(record.name()), record.record_type()))
+                .or_insert_with(Vec::new)
+                .push(record);
+        }
+        local
+    }
+
+    /// Answers a class IN query with the records of its name and type, or with a
+    /// CNAME of the name
+    pub fn lookup(&self, query: &Query, recursion_desired: bool) -> Option<Message> {
+        if query.query_class() != DNSClass::IN {
+            return None;
+        }
+        let name = LowerName::from(query.name());
+        let records = self
+            .records
+            .get(&(name.clone(), query.query_type()))
+            .or_else(|| self.records.get(&(name, RecordType::CNAME)))?;
+
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_authoritative(true)
+            .set_recursion_desired(recursion_desired)
+            .set_recursion_available(true)
+            .add_query(query.clone())
+            .add_answers(records.iter().cloned());
+        Some(message)
+    }
+}
+
+/// Root hints (e.g. named.root): the NS records of the root name and the
+/// addresses of those name servers
+pub struct RootHints {
+    records: HashMap<RecordType, Vec<Record>>,
+    glue: Vec<Record>,
+}
+
+impl RootHints {
+    /// Loads and parses the root hints file at `path`
+    pub async fn load(path: &str) -> Result<Self> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read root hints {path}"))?;
+        let (_, records) = Parser::new(contents, Some(PathBuf::from(path)), Some(Name::root()))
+            .parse()
+            .with_context(|| format!("Failed to parse root hints {path}"))?;
+
+        let root = LowerName::from(Name::root());
+        let mut hints = Self {
+            records: HashMap::new(),
+            glue: Vec::new(),
+        };
+        for (key, record_set) in records {
+            let records = record_set.records_without_rrsigs().cloned();
+            if *key.name() == root {
+                hints
+                    .records
+                    .entry(key.record_type)
+                    .or_default()
+                    .extend(records);
+            } else if matches!(key.record_type, RecordType::A | RecordType::AAAA) {
+                hints.glue.extend(records);
+            }
+        }
+        if !hints.records.contains_key(&RecordType::NS) {
+            tracing::warn!("Root hints {} have no NS records for the root", path);
+        }
+        tracing::info!(
+            "Loaded root hints {} ({} name servers)",
+            path,
+            hints.records.get(&RecordType::NS).map_or(0, Vec::len)
+        );
+        Ok(hints)
+    }
+
+    /// Answers a query for the root name with the hints of its type, adding the
+    /// name server addresses to NS answers; `None` if there are no such hints
+    pub fn lookup(&self, query: &Query, recursion_desired: bool) -> Option<Message> {
+        let records = self.records.get(&query.query_type())?;
+
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(recursion_desired)
+            .set_recursion_available(true)
+            .add_query(query.clone())
+            .add_answers(records.iter().cloned());
+        if query.query_type() == RecordType::NS {
+            let targets: Vec<_> = records
+                .iter()
+                .filter_map(|record| record.data().as_ns())
+                .map(|ns| LowerName::from(&ns.0))
+                .collect();
+            message.add_additionals(
+                self.glue
+                    .iter()
+                    .filter(|glue| targets.contains(&LowerName::from(glue.name())))
+                    .cloned(),
+            );
+        }
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::{RData, rdata::A};
+
+    const ZONE: &str = "\
+$ORIGIN example.com.
+$TTL 300
+@ IN SOA ns.example.com. hostmaster.example.com. 1 3600 600 86400 300
+a.b IN A 192.0.2.1
+";
+
+    async fn zone() -> LocalZone {
+        let path = std::env::temp_dir().join(format!("race-dns-proxy-{}.zone", std::process::id()));
+        std::fs::write(&path, ZONE).unwrap();
+        let zone = LocalZone::load("example.com", path.to_str().unwrap())
+            .await
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        zone
+    }
+
+    fn query(name: &str, query_class: DNSClass) -> Query {
+        let mut query = Query::query(Name::from_ascii(name).unwrap(), RecordType::A);
+        query.set_query_class(query_class);
+        query
+    }
+
+    #[tokio::test]
+    async fn empty_non_terminal_is_nodata() {
+        let zone = zone().await;
+
+        let message = zone
+            .lookup(&query("b.example.com.", DNSClass::IN), true)
+            .unwrap();
+        assert_eq!(message.response_code(), ResponseCode::NoError);
+        assert!(message.answers().is_empty());
+        assert_eq!(message.name_servers().len(), 1);
+
+        let message = zone
+            .lookup(&query("c.example.com.", DNSClass::IN), true)
+            .unwrap();
+        assert_eq!(message.response_code(), ResponseCode::NXDomain);
+
+        let message = zone
+            .lookup(&query("a.b.example.com.", DNSClass::IN), true)
+            .unwrap();
+        assert_eq!(message.answers().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn other_classes_are_not_answered() {
+        let zone = zone().await;
+        assert!(
+            zone.lookup(&query("a.b.example.com.", DNSClass::CH), true)
+                .is_none()
+        );
+
+        let records = LocalRecords::new(vec![Record::from_rdata(
+            Name::from_ascii("local.test.").unwrap(),
+            300,
+            RData::A(A::new(192, 0, 2, 1)),
+        )]);
+        assert!(
+            records
+                .lookup(&query("local.test.", DNSClass::IN), true)
+                .is_some()
+        );
+        assert!(
+            records
+                .lookup(&query("local.test.", DNSClass::CH), true)
+                .is_none()
+        );
+    }
+}